@@ -0,0 +1,106 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::applet::interface::Payload;
+
+/// The topics applets subscribed to, along with the last value published on
+/// each of them.
+#[derive(Debug, Default)]
+pub(crate) struct Bus {
+    retained: HashMap<String, Payload>,
+    subscriptions: HashMap<u32, HashSet<String>>,
+}
+
+impl Bus {
+    /// Subscribes the applet `id` to `topic`, returning the value retained on
+    /// it, if any.
+    pub fn subscribe(&mut self, id: u32, topic: String) -> Option<Payload> {
+        let retained = self.retained.get(&topic).cloned();
+        self.subscriptions.entry(id).or_default().insert(topic);
+        retained
+    }
+
+    pub fn unsubscribe(&mut self, id: u32, topic: &str) {
+        if let Some(topics) = self.subscriptions.get_mut(&id) {
+            topics.remove(topic);
+        }
+    }
+
+    /// Retains `payload` as the value of `topic` and returns the applets it
+    /// must be delivered to, which excludes the publisher `id`.
+    pub fn publish(&mut self, id: u32, topic: &str, payload: Payload) -> Vec<u32> {
+        self.retained.insert(topic.to_string(), payload);
+        self.subscriptions
+            .iter()
+            .filter(|(subscriber, topics)| **subscriber != id && topics.contains(topic))
+            .map(|(subscriber, _)| *subscriber)
+            .collect()
+    }
+
+    /// Drops every subscription of the applet `id`.
+    pub fn remove(&mut self, id: u32) {
+        self.subscriptions.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(value: u32) -> Payload {
+        Payload::new(&value).unwrap()
+    }
+
+    fn sorted(mut ids: Vec<u32>) -> Vec<u32> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn retained() {
+        let mut bus = Bus::default();
+        assert_eq!(bus.subscribe(1, "volume".to_string()), None);
+
+        bus.publish(2, "volume", payload(10));
+        bus.publish(2, "volume", payload(20));
+        assert_eq!(bus.subscribe(3, "volume".to_string()), Some(payload(20)));
+        assert_eq!(bus.subscribe(3, "battery".to_string()), None);
+    }
+
+    #[test]
+    fn fan_out() {
+        let mut bus = Bus::default();
+        bus.subscribe(1, "volume".to_string());
+        bus.subscribe(2, "volume".to_string());
+        bus.subscribe(3, "battery".to_string());
+
+        assert_eq!(sorted(bus.publish(4, "volume", payload(1))), [1, 2]);
+        assert_eq!(bus.publish(4, "battery", payload(1)), [3]);
+        assert!(bus.publish(4, "network", payload(1)).is_empty());
+    }
+
+    #[test]
+    fn publisher_is_skipped() {
+        let mut bus = Bus::default();
+        bus.subscribe(1, "volume".to_string());
+        bus.subscribe(2, "volume".to_string());
+
+        assert_eq!(bus.publish(1, "volume", payload(1)), [2]);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let mut bus = Bus::default();
+        bus.subscribe(1, "volume".to_string());
+        bus.subscribe(1, "battery".to_string());
+        bus.subscribe(2, "volume".to_string());
+
+        bus.unsubscribe(1, "volume");
+        assert_eq!(bus.publish(3, "volume", payload(1)), [2]);
+        assert_eq!(bus.publish(3, "battery", payload(1)), [1]);
+
+        bus.remove(1);
+        assert!(bus.publish(3, "battery", payload(2)).is_empty());
+        // Unsubscribing keeps the retained value for later subscribers.
+        assert_eq!(bus.subscribe(1, "battery".to_string()), Some(payload(2)));
+    }
+}
@@ -1,338 +1,1850 @@
 use std::{
-    error::Error,
-    fs::remove_file,
-    path::Path,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    env, fmt,
+    fs::{File, Permissions, remove_file, set_permissions},
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, ExitStatus},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use iced::{
-    futures::{SinkExt, Stream, channel::mpsc},
+    Size,
+    futures::{SinkExt, Stream, StreamExt, channel::mpsc},
     stream,
 };
+use iced_core::layout::Limits;
 use serde::{Serialize, de::DeserializeOwned};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{UnixListener, UnixStream},
-    sync::{broadcast, mpsc::unbounded_channel},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    process::{ChildStderr, ChildStdout},
+    sync::{
+        broadcast,
+        mpsc::{UnboundedSender, WeakUnboundedSender, unbounded_channel},
+        watch,
+    },
+    task::JoinHandle,
 };
 
 use crate::{
-    applet::interface::{Applet, AppletMessage, RuntimeMessage, RuntimeRequest},
+    applet::{
+        bus::Bus,
+        control::{control_socket_path, serve_control},
+        discovery::{self, DiscoveryEvent},
+        exec::LaunchError,
+        interface::{
+            Applet, AppletEvent, AppletInfo, AppletMessage, AppletPosition, AppletRequest,
+            AppletState, Direction, DisconnectPolicy, Handshake, Hello, RejectReason,
+            RuntimeMessage, RuntimeRequest,
+        },
+        layout::{LayoutEntry, PanelLayout, layout_path},
+        log::{LogBuffer, LogFile, LogSink, LogStream, capture},
+        settings::{self, Config},
+    },
     runtime,
 };
 
-pub fn subscribe() -> impl Stream<Item = RuntimeMessage> {
-    stream::channel(100, |mut tx: mpsc::Sender<RuntimeMessage>| async move {
-        let (stream_sender, mut stream_receiver) = unbounded_channel();
-        let mut output = tx.clone();
+/// The environment variable through which launched applets learn the socket of
+/// the runtime that spawned them.
+pub const SOCKET_ENV: &str = "AZALEA_SOCKET";
 
-        runtime().spawn(async move {
-            let socket = Path::new("/tmp/sock");
+/// The environment variable through which launched applets receive the secret
+/// they present to the runtime in their [`Hello`].
+pub const TOKEN_ENV: &str = "AZALEA_TOKEN";
 
-            if socket.exists() {
-                remove_file(socket).unwrap();
-            }
-            let listener = UnixListener::bind(socket).unwrap();
+/// The environment variable naming the definition an applet was launched
+/// from, which it presents in its [`Hello`] so that a runtime that did not
+/// launch it can adopt it.
+pub const APPLET_ENV: &str = "AZALEA_APPLET";
+
+/// The instance name used by [`subscribe`] and by applets started without
+/// [`SOCKET_ENV`] set.
+pub const DEFAULT_INSTANCE: &str = "panel";
+
+/// The environment variable through which an applet selects the
+/// [`WireFormat`] it speaks, either `bincode` (the default) or `json`.
+pub const WIRE_FORMAT_ENV: &str = "AZALEA_WIRE_FORMAT";
 
-            let (runtime_sender, mut runtime_receiver) = unbounded_channel();
+/// The largest frame accepted from a peer, in bytes.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
-            output
-                .send(RuntimeMessage::Ready(runtime_sender.clone()))
-                .await
-                .unwrap();
+/// The encoding of the frames on an applet connection.
+///
+/// The format is picked by the applet and detected by the runtime from the
+/// first bytes it receives, see [`WireFormat::detect`]. The runtime answers in
+/// the same format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// A little-endian `u32` length followed by that many bytes of bincode.
+    #[default]
+    Bincode,
+    /// One JSON document per line, for debugging and non-Rust applets.
+    Json,
+}
 
-            let applets = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+impl WireFormat {
+    /// Returns the format selected through [`WIRE_FORMAT_ENV`].
+    pub fn from_env() -> Self {
+        match env::var(WIRE_FORMAT_ENV).as_deref() {
+            Ok("json") => WireFormat::Json,
+            _ => WireFormat::Bincode,
+        }
+    }
 
+    /// Returns the format of a connection starting with `prefix`, which holds
+    /// at least four bytes.
+    ///
+    /// JSON frames start with `{` or, for unit variants, `"`, followed by
+    /// text. Bincode frames start with their little-endian length, which is
+    /// at most [`MAX_FRAME_SIZE`], while any four bytes of JSON text read as
+    /// a larger one.
+    pub fn detect(prefix: &[u8]) -> Self {
+        match prefix {
+            [b'{' | b'"', _, _, _, ..]
+                if u32::from_le_bytes(prefix[..4].try_into().unwrap()) as usize
+                    > MAX_FRAME_SIZE =>
             {
-                let applets = applets.clone();
-                runtime().spawn(async move {
-                    loop {
-                        if let Some(request) = runtime_receiver.recv().await {
+                WireFormat::Json
+            }
+            _ => WireFormat::Bincode,
+        }
+    }
+
+    /// Appends `message` to `dst` as a single frame.
+    pub fn encode<T>(self, message: &T, dst: &mut BytesMut) -> Result<(), ConnectionError>
+    where
+        T: Serialize,
+    {
+        match self {
+            WireFormat::Bincode => encode_bincode(message, dst),
+            WireFormat::Json => encode_json(message, dst),
+        }
+    }
+
+    /// Removes and decodes the first frame in `src`, or returns `None` if it
+    /// has not been received in full yet.
+    pub fn decode<T>(self, src: &mut BytesMut) -> Result<Option<T>, ConnectionError>
+    where
+        T: DeserializeOwned,
+    {
+        self.decode_from(src, &mut 0)
+    }
+
+    /// Like [`WireFormat::decode`], but skips the first `scanned` bytes of
+    /// `src` when looking for the end of a JSON frame, and updates it to the
+    /// bytes scanned so far if no frame is complete yet.
+    fn decode_from<T>(
+        self,
+        src: &mut BytesMut,
+        scanned: &mut usize,
+    ) -> Result<Option<T>, ConnectionError>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            WireFormat::Bincode => decode_bincode(src),
+            WireFormat::Json => decode_json(src, scanned),
+        }
+    }
+}
+
+/// An error on an applet connection.
+#[derive(Debug)]
+pub enum ConnectionError {
+    Io(io::Error),
+    Encode(bincode::error::EncodeError),
+    Decode(bincode::error::DecodeError),
+    Json(serde_json::Error),
+    /// The peer announced a frame larger than [`MAX_FRAME_SIZE`].
+    FrameTooLarge(usize),
+    /// The peer closed the connection in the middle of a frame.
+    Reset,
+    /// The peer closed the connection before the handshake completed.
+    Handshake,
+    /// The other end of an internal channel was dropped.
+    Closed,
+    /// The runtime closed the connection.
+    Eof,
+    /// Another runtime is already listening on the socket.
+    InUse(PathBuf),
+    /// The runtime does not know the applet, e.g. as it was launched by a
+    /// previous runtime that did not save it in its layout.
+    UnknownApplet,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Io(error) => write!(f, "I/O error: {error}"),
+            ConnectionError::Encode(error) => write!(f, "failed to encode frame: {error}"),
+            ConnectionError::Decode(error) => write!(f, "failed to decode frame: {error}"),
+            ConnectionError::Json(error) => write!(f, "invalid JSON frame: {error}"),
+            ConnectionError::FrameTooLarge(size) => write!(
+                f,
+                "frame of {size} bytes exceeds the limit of {MAX_FRAME_SIZE} bytes"
+            ),
+            ConnectionError::Reset => write!(f, "connection reset by peer"),
+            ConnectionError::Handshake => write!(f, "connection closed during handshake"),
+            ConnectionError::Closed => write!(f, "channel closed"),
+            ConnectionError::Eof => write!(f, "connection closed by the runtime"),
+            ConnectionError::InUse(socket) => {
+                write!(f, "a runtime is already listening on {}", socket.display())
+            }
+            ConnectionError::UnknownApplet => write!(f, "the runtime does not know this applet"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Io(error) => Some(error),
+            ConnectionError::Encode(error) => Some(error),
+            ConnectionError::Decode(error) => Some(error),
+            ConnectionError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(error: io::Error) -> Self {
+        ConnectionError::Io(error)
+    }
+}
+
+impl From<bincode::error::EncodeError> for ConnectionError {
+    fn from(error: bincode::error::EncodeError) -> Self {
+        ConnectionError::Encode(error)
+    }
+}
+
+impl From<bincode::error::DecodeError> for ConnectionError {
+    fn from(error: bincode::error::DecodeError) -> Self {
+        ConnectionError::Decode(error)
+    }
+}
+
+impl From<serde_json::Error> for ConnectionError {
+    fn from(error: serde_json::Error) -> Self {
+        ConnectionError::Json(error)
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ConnectionError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        ConnectionError::Closed
+    }
+}
+
+/// Returns the socket path of the runtime named `instance`, which is
+/// `$XDG_RUNTIME_DIR/azalea/<instance>.sock`.
+///
+/// The `azalea` directory is created if needed and restricted to the current
+/// user.
+pub fn socket_path(instance: &str) -> io::Result<PathBuf> {
+    let path = xdg::BaseDirectories::with_prefix("azalea")
+        .place_runtime_file(format!("{instance}.sock"))?;
+    if let Some(dir) = path.parent() {
+        set_permissions(dir, Permissions::from_mode(0o700))?;
+    }
+    Ok(path)
+}
+
+/// Returns the socket path an applet should connect to, taken from
+/// [`SOCKET_ENV`] and falling back to the [`DEFAULT_INSTANCE`] socket.
+pub fn applet_socket_path() -> io::Result<PathBuf> {
+    match env::var_os(SOCKET_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => socket_path(DEFAULT_INSTANCE),
+    }
+}
+
+/// Runs the applet runtime of the [`DEFAULT_INSTANCE`].
+pub fn subscribe() -> impl Stream<Item = RuntimeMessage> {
+    subscribe_instance(DEFAULT_INSTANCE)
+}
+
+/// Runs the applet runtime of the named `instance`, so that several hosts
+/// (e.g. a panel and a dock) can serve their own applets side by side.
+pub fn subscribe_instance(instance: impl Into<String>) -> impl Stream<Item = RuntimeMessage> {
+    let instance = instance.into();
+    stream::channel(
+        100,
+        move |mut tx: mpsc::Sender<RuntimeMessage>| async move {
+            let (stream_sender, mut stream_receiver) = unbounded_channel();
+
+            runtime().spawn(async move {
+                let (listener, control_listener, socket) = match bind(&instance) {
+                    Ok(bound) => bound,
+                    Err(error) => {
+                        _ = stream_sender.send(RuntimeMessage::Error(Arc::new(error)));
+                        return;
+                    }
+                };
+
+                let (runtime_sender, mut runtime_receiver) = unbounded_channel();
+
+                if stream_sender
+                    .send(RuntimeMessage::Ready(runtime_sender.clone()))
+                    .is_err()
+                {
+                    return;
+                }
+
+                let layout_path = match layout_path(&instance) {
+                    Ok(path) => Some(path),
+                    Err(error) => {
+                        _ = stream_sender.send(RuntimeMessage::LayoutError(Arc::new(error.into())));
+                        None
+                    }
+                };
+                let applets: AppletTable = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+                let pending: PendingRestarts = Arc::default();
+                let bus: SharedBus = Arc::default();
+                let logs = LogSink {
+                    messages: stream_sender.clone(),
+                    persist: Arc::default(),
+                };
+                let context = RuntimeContext {
+                    applets: applets.clone(),
+                    socket,
+                    logs: logs.clone(),
+                    messages: stream_sender.clone(),
+                    direction: Arc::new(watch::Sender::new(None)),
+                    pending: pending.clone(),
+                };
+
+                // Only the host holds on to the runtime with a strong sender,
+                // the runtime's own tasks stop once it lets go of it.
+                let control = runtime().spawn(serve_control(
+                    control_listener,
+                    applets.clone(),
+                    pending.clone(),
+                    runtime_sender.downgrade(),
+                    stream_sender.clone(),
+                ));
+
+                {
+                    let context = context.clone();
+                    let applets = applets.clone();
+                    let stream_sender = stream_sender.clone();
+                    let logs = logs.clone();
+                    let runtime_sender = runtime_sender.downgrade();
+                    let layout_path = layout_path.clone();
+                    runtime().spawn(async move {
+                        let layouts = layout_writer(layout_path.clone(), stream_sender.clone());
+                        let mut layout = match layout_path.as_deref().map(PanelLayout::load) {
+                            Some(Ok(layout)) => layout,
+                            Some(Err(error)) => {
+                                _ = stream_sender
+                                    .send(RuntimeMessage::LayoutError(Arc::new(error)));
+                                PanelLayout::default()
+                            }
+                            None => PanelLayout::default(),
+                        };
+                        let definitions = load_definitions(&stream_sender);
+                        for entry in &layout.applets {
+                            let Some(info) = definitions.iter().find(|info| info.id == entry.id)
+                            else {
+                                _ = stream_sender.send(unknown_definition(&entry.id));
+                                continue;
+                            };
+                            spawn_applet(
+                                &context,
+                                info.clone(),
+                                entry.order,
+                                entry.position.clone(),
+                                settings::config(&info.settings, &entry.settings),
+                                0,
+                            )
+                            .await;
+                        }
+
+                        let mut watcher: Option<JoinHandle<()>> = None;
+                        while let Some(request) = tokio::select! {
+                            request = runtime_receiver.recv() => request,
+                            () = stream_sender.closed() => None,
+                        } {
                             match request {
                                 RuntimeRequest::Launch {
                                     info,
                                     order,
                                     position,
                                 } => {
-                                    let (applet_sender, applet_receiver) = broadcast::channel(100);
-                                    let Ok(child) = info.launch() else {
+                                    let config =
+                                        settings::config(&info.settings, &toml::Table::new());
+                                    spawn_applet(&context, info, order, position, config, 0).await;
+                                }
+                                RuntimeRequest::Stop(id) | RuntimeRequest::Remove(id) => {
+                                    let remove = matches!(request, RuntimeRequest::Remove(_));
+                                    let (applet, running) = match take_applet(&applets, id).await {
+                                        Some(applet) => (applet, true),
+                                        None => match cancel_restart(&pending, id).await {
+                                            Some(applet) => (applet, false),
+                                            None => continue,
+                                        },
+                                    };
+                                    if remove
+                                        && let Some(index) = layout.find(
+                                            &applet.info.id,
+                                            &applet.position,
+                                            applet.order,
+                                        )
+                                    {
+                                        layout.applets.remove(index);
+                                        _ = layouts.send(layout.clone());
+                                    }
+                                    if running {
+                                        let stream_sender = stream_sender.clone();
+                                        runtime().spawn(async move {
+                                            stop_applet(&applet, &stream_sender).await;
+                                        });
+                                    }
+                                }
+                                RuntimeRequest::Restart(id) | RuntimeRequest::Reload(id) => {
+                                    let reload = matches!(request, RuntimeRequest::Reload(_));
+                                    let (applet, running) = match take_applet(&applets, id).await {
+                                        Some(applet) => (applet, true),
+                                        None => match cancel_restart(&pending, id).await {
+                                            Some(applet) => (applet, false),
+                                            None => continue,
+                                        },
+                                    };
+                                    let context = context.clone();
+                                    runtime().spawn(async move {
+                                        if running {
+                                            stop_applet(&applet, &context.messages).await;
+                                        }
+                                        let info = if reload {
+                                            load_definitions(&context.messages)
+                                                .into_iter()
+                                                .find(|i| i.id == applet.info.id)
+                                                .unwrap_or(applet.info)
+                                        } else {
+                                            applet.info
+                                        };
+                                        let config = settings::config(
+                                            &info.settings,
+                                            &settings::to_table(&applet.config),
+                                        );
+                                        spawn_applet(
+                                            &context,
+                                            info,
+                                            applet.order,
+                                            applet.position,
+                                            config,
+                                            0,
+                                        )
+                                        .await;
+                                    });
+                                }
+                                RuntimeRequest::ReloadOnChange(enabled) => {
+                                    if let Some(watcher) = watcher.take() {
+                                        watcher.abort();
+                                    }
+                                    if enabled {
+                                        watcher = Some(runtime().spawn(reload_on_change(
+                                            applets.clone(),
+                                            runtime_sender.clone(),
+                                        )));
+                                    }
+                                }
+                                RuntimeRequest::PersistLogs(enabled) => {
+                                    logs.persist.store(enabled, Ordering::Relaxed);
+                                }
+                                RuntimeRequest::Add {
+                                    id,
+                                    order,
+                                    position,
+                                } => {
+                                    let Some(info) = load_definitions(&stream_sender)
+                                        .into_iter()
+                                        .find(|info| info.id == id)
+                                    else {
+                                        _ = stream_sender.send(unknown_definition(&id));
+                                        continue;
+                                    };
+                                    layout.applets.push(LayoutEntry {
+                                        id,
+                                        position: position.clone(),
+                                        order,
+                                        settings: toml::Table::new(),
+                                    });
+                                    _ = layouts.send(layout.clone());
+                                    let config =
+                                        settings::config(&info.settings, &toml::Table::new());
+                                    spawn_applet(&context, info, order, position, config, 0).await;
+                                }
+                                RuntimeRequest::Move {
+                                    id,
+                                    order,
+                                    position,
+                                } => {
+                                    let mut table = applets.lock().await;
+                                    let Some((applet, _)) =
+                                        table.iter_mut().find(|(a, _)| a.id == id)
+                                    else {
                                         continue;
                                     };
-                                    let applet = Applet {
-                                        id: child.id(),
+                                    if let Some(index) =
+                                        layout.find(&applet.info.id, &applet.position, applet.order)
+                                    {
+                                        let entry = &mut layout.applets[index];
+                                        entry.position = position.clone();
+                                        entry.order = order;
+                                        _ = layouts.send(layout.clone());
+                                    }
+                                    applet.position = position.clone();
+                                    applet.order = order;
+                                    drop(table);
+                                    _ = stream_sender.send(RuntimeMessage::Moved {
+                                        id,
                                         order,
-                                        info,
-                                        sender: applet_sender,
-                                        limits: None,
-                                        intrinsic: None,
-                                        size: None,
-                                        margin: Default::default(),
-                                        process: Arc::new(Mutex::new(child)),
                                         position,
+                                    });
+                                }
+                                RuntimeRequest::Configure { id, config } => {
+                                    let mut table = applets.lock().await;
+                                    let Some((applet, _)) =
+                                        table.iter_mut().find(|(a, _)| a.id == id)
+                                    else {
+                                        continue;
                                     };
-                                    output
-                                        .send(RuntimeMessage::New(applet.clone()))
-                                        .await
-                                        .unwrap();
-                                    applets.lock().await.push((applet, applet_receiver));
+                                    for (key, value) in config {
+                                        let Some(value) = applet
+                                            .info
+                                            .settings
+                                            .iter()
+                                            .find(|setting| setting.key == key)
+                                            .and_then(|setting| setting.validate(value))
+                                        else {
+                                            continue;
+                                        };
+                                        applet.config.insert(key, value);
+                                    }
+                                    if let Some(index) =
+                                        layout.find(&applet.info.id, &applet.position, applet.order)
+                                    {
+                                        layout.applets[index].settings =
+                                            settings::to_table(&applet.config);
+                                        _ = layouts.send(layout.clone());
+                                    }
+                                    let config = applet.config.clone();
+                                    _ = applet.sender.send(AppletEvent::Config(config.clone()));
+                                    drop(table);
+                                    _ = stream_sender.send(RuntimeMessage::Configured(id, config));
                                 }
                             }
                         }
-                    }
-                });
-            }
-            loop {
-                if let Ok((mut stream, _)) = listener.accept().await {
-                    let pid = stream.peer_cred().unwrap().pid().unwrap().clone();
-
-                    let Some((applet, mut receiver)) = applets
-                        .lock()
-                        .await
-                        .iter()
-                        .find(|(a, _)| a.id == pid as u32)
-                        .map(|(a, r)| (a.clone(), r.resubscribe()))
-                    else {
-                        _ = stream.shutdown().await;
-                        continue;
-                    };
-
-                    let (mut read, mut write) = stream.into_split();
-                    let mut connection = BufConnection::new();
+                        if let Some(watcher) = watcher {
+                            watcher.abort();
+                        }
+                    });
+                }
+                {
+                    let context = context.clone();
+                    let applets = applets.clone();
                     let stream_sender = stream_sender.clone();
                     runtime().spawn(async move {
-                        loop {
-                            if let Ok(Some(request)) = connection.read_frame(&mut read).await {
-                                stream_sender
-                                    .send(RuntimeMessage::Request(request, applet.id))
-                                    .unwrap();
+                        let mut interval = tokio::time::interval(REAP_INTERVAL);
+                        while !stream_sender.is_closed() {
+                            interval.tick().await;
+                            let mut exited = Vec::new();
+                            applets.lock().await.retain(|(applet, _)| {
+                                let Some(process) = &applet.process else {
+                                    return true;
+                                };
+                                match lock(process).try_wait() {
+                                    Ok(Some(status)) => {
+                                        exited.push((applet.clone(), status));
+                                        false
+                                    }
+                                    _ => true,
+                                }
+                            });
+                            for (applet, status) in exited {
+                                _ = stream_sender.send(RuntimeMessage::Shutdown(applet.id, status));
+                                let restarts = if applet.launched_at.elapsed() >= STABLE_UPTIME {
+                                    0
+                                } else {
+                                    applet.restarts
+                                };
+                                let Some(delay) = applet.info.restart.delay(status, restarts)
+                                else {
+                                    continue;
+                                };
+                                // The entry is inserted before the task can
+                                // look it up, since the lock is held until then.
+                                let mut pending = context.pending.lock().await;
+                                let id = applet.id;
+                                let task = {
+                                    let context = context.clone();
+                                    runtime().spawn(async move {
+                                        tokio::time::sleep(delay).await;
+                                        let Some(restart) =
+                                            context.pending.lock().await.remove(&id)
+                                        else {
+                                            return;
+                                        };
+                                        let applet = restart.applet;
+                                        spawn_applet(
+                                            &context,
+                                            applet.info,
+                                            applet.order,
+                                            applet.position,
+                                            applet.config,
+                                            restart.restarts,
+                                        )
+                                        .await;
+                                    })
+                                };
+                                pending.insert(
+                                    id,
+                                    PendingRestart {
+                                        applet,
+                                        restarts: restarts + 1,
+                                        task,
+                                    },
+                                );
                             }
                         }
+                        for (_, restart) in context.pending.lock().await.drain() {
+                            restart.task.abort();
+                        }
                     });
+                }
+                drop(runtime_sender);
+                loop {
+                    let stream = tokio::select! {
+                        stream = accept(&listener, &stream_sender) => stream,
+                        () = stream_sender.closed() => break,
+                    };
+                    let context = context.clone();
+                    let bus = bus.clone();
+                    let layout_path = layout_path.clone();
                     runtime().spawn(async move {
-                        loop {
-                            if let Ok(event) = receiver.recv().await {
-                                write_frame(event, &mut write).await.unwrap();
-                            }
-                        }
+                        accept_applet(stream, &context, &bus, layout_path.as_deref()).await;
                     });
                 }
+                // Dropping the listeners closes the sockets, so that the next
+                // runtime of this instance can bind them.
+                control.abort();
+            });
+
+            while let Some(message) = stream_receiver.recv().await {
+                if tx.send(message).await.is_err() {
+                    break;
+                }
             }
-        });
-        runtime().spawn(async move {
-            loop {
-                if let Some(message) = stream_receiver.recv().await {
-                    tx.send(message).await.unwrap()
+        },
+    )
+}
+
+/// Accepts a connection on `listener`, reporting failures to the host and
+/// backing off before trying again, since they tend to persist (e.g. when the
+/// runtime ran out of file descriptors).
+pub(crate) async fn accept(
+    listener: &UnixListener,
+    messages: &UnboundedSender<RuntimeMessage>,
+) -> UnixStream {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => return stream,
+            Err(error) => {
+                _ = messages.send(RuntimeMessage::Error(Arc::new(error.into())));
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Binds the applet and control sockets of the runtime named `instance`.
+fn bind(instance: &str) -> Result<(UnixListener, UnixListener, PathBuf), ConnectionError> {
+    let socket = socket_path(instance)?;
+    let listener = bind_socket(&socket)?;
+    let control = bind_socket(&control_socket_path(instance)?)?;
+    Ok((listener, control, socket))
+}
+
+/// Binds a listening socket at `socket`, replacing a stale socket left behind
+/// by a previous run but refusing to take over one a live runtime listens on.
+fn bind_socket(socket: &Path) -> Result<UnixListener, ConnectionError> {
+    match std::os::unix::net::UnixStream::connect(socket) {
+        Ok(_) => return Err(ConnectionError::InUse(socket.to_path_buf())),
+        Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => remove_file(socket)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+    let listener = UnixListener::bind(socket)?;
+    set_permissions(socket, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Identifies a connected applet by the token in its [`Hello`], falling back
+/// to the pid of the peer for applets launched without one, and serves it.
+///
+/// Applets started by a previous runtime are adopted into the place in the
+/// layout of the definition they name in their [`Hello`], other connections
+/// that cannot be matched to a launched applet are rejected.
+async fn accept_applet(
+    stream: UnixStream,
+    context: &RuntimeContext,
+    bus: &SharedBus,
+    layout_path: Option<&Path>,
+) {
+    let (applets, messages) = (&context.applets, &context.messages);
+    let pid = stream
+        .peer_cred()
+        .ok()
+        .and_then(|cred| cred.pid())
+        .map(|pid| pid as u32);
+    let mut framed = Framed::new(stream);
+    let hello = match read_hello(&mut framed).await {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return,
+        Err(error) => {
+            _ = messages.send(match pid {
+                Some(pid) => RuntimeMessage::Rejected(pid, format!("invalid hello: {error}")),
+                None => RuntimeMessage::Error(Arc::new(error)),
+            });
+            return;
+        }
+    };
+
+    let identified = applets
+        .lock()
+        .await
+        .iter()
+        .find(|(a, _)| match &hello.token {
+            Some(token) => a.token == *token,
+            None => Some(a.id) == pid,
+        })
+        .map(|(a, r)| (a.clone(), r.resubscribe()));
+    let identified = match identified {
+        Some(identified) => Some(identified),
+        None => adopt_applet(&hello, pid, context, layout_path).await,
+    };
+    let Some((applet, events)) = identified else {
+        _ = framed
+            .write_frame(&Handshake::Rejected(RejectReason::UnknownApplet))
+            .await;
+        if let Some(pid) = pid {
+            _ = messages.send(RuntimeMessage::Rejected(
+                pid,
+                RejectReason::UnknownApplet.to_string(),
+            ));
+        }
+        return;
+    };
+
+    let result = serve_applet(&applet, framed, &hello, events, context, bus).await;
+    bus.lock().await.remove(applet.id);
+    if let Err(error) = result {
+        _ = messages.send(RuntimeMessage::Disconnected(applet.id, Arc::new(error)));
+    }
+    if applet.process.is_none() && take_applet(applets, applet.id).await.is_some() {
+        _ = messages.send(RuntimeMessage::Detached(applet.id));
+    }
+}
+
+/// Registers an applet the runtime did not launch, placing it at the first
+/// entry of the layout for its definition that neither a running applet nor
+/// one waiting to be relaunched occupies.
+///
+/// Applets that name a definition the layout has no free place for are not
+/// adopted, so that a connection cannot take over a place on the panel just
+/// by naming a definition.
+async fn adopt_applet(
+    hello: &Hello,
+    pid: Option<u32>,
+    context: &RuntimeContext,
+    layout_path: Option<&Path>,
+) -> Option<(Applet, broadcast::Receiver<AppletEvent>)> {
+    let (id, pid) = (hello.applet.as_ref()?, pid?);
+    let layout = PanelLayout::load(layout_path?).ok()?;
+    if !layout.applets.iter().any(|entry| entry.id == *id) {
+        return None;
+    }
+    let info = load_definitions(&context.messages)
+        .into_iter()
+        .find(|info| info.id == *id)?;
+
+    let mut table = context.applets.lock().await;
+    let pending = context.pending.lock().await;
+    if table.iter().any(|(a, _)| a.id == pid) {
+        return None;
+    }
+    let occupied = |entry: &LayoutEntry| {
+        table
+            .iter()
+            .map(|(a, _)| a)
+            .chain(pending.values().map(|restart| &restart.applet))
+            .any(|a| {
+                a.info.id == entry.id && a.position == entry.position && a.order == entry.order
+            })
+    };
+    let entry = layout
+        .applets
+        .iter()
+        .find(|entry| entry.id == info.id && !occupied(entry))?;
+    let (position, order) = (entry.position.clone(), entry.order);
+    let config = settings::config(&info.settings, &entry.settings);
+    drop(pending);
+
+    let (sender, receiver) = broadcast::channel(100);
+    let applet = Applet {
+        id: pid,
+        order,
+        info,
+        sender,
+        limits: None,
+        intrinsic: None,
+        surfaces: HashMap::new(),
+        size: None,
+        margin: Default::default(),
+        process: None,
+        position,
+        restarts: 0,
+        launched_at: Instant::now(),
+        token: hello.token.clone().unwrap_or_default(),
+        log: LogBuffer::default(),
+        state: AppletState::default(),
+        config,
+    };
+    let events = receiver.resubscribe();
+    table.push((applet.clone(), receiver));
+    drop(table);
+    _ = context.messages.send(RuntimeMessage::New(applet.clone()));
+    Some((applet, events))
+}
+
+async fn read_hello(framed: &mut Framed<UnixStream>) -> Result<Option<Hello>, ConnectionError> {
+    framed.detect_format().await?;
+    framed.read_frame().await
+}
+
+/// Completes the handshake with an identified applet, then relays its
+/// requests to the host and the host's events to it until either side
+/// disconnects.
+async fn serve_applet(
+    applet: &Applet,
+    framed: Framed<UnixStream>,
+    hello: &Hello,
+    mut events: broadcast::Receiver<AppletEvent>,
+    context: &RuntimeContext,
+    bus: &SharedBus,
+) -> Result<(), ConnectionError> {
+    let (applets, messages) = (&context.applets, &context.messages);
+    let (mut read, mut write) = framed.into_split();
+
+    let protocol = match Hello::current().negotiate(hello) {
+        Ok(negotiated) => {
+            write
+                .write_frame(&Handshake::Accepted(negotiated.clone()))
+                .await?;
+            let protocol = negotiated.protocol;
+            messages.send(RuntimeMessage::Connected(applet.id, negotiated))?;
+            protocol
+        }
+        Err(reason) => {
+            _ = write
+                .write_frame(&Handshake::Rejected(RejectReason::Incompatible(
+                    reason.clone(),
+                )))
+                .await;
+            messages.send(RuntimeMessage::Rejected(applet.id, reason))?;
+            return Ok(());
+        }
+    };
+    let config = AppletEvent::Config(applet.config.clone());
+    if !applet.config.is_empty() && config.protocol() <= protocol {
+        write.write_frame(&config).await?;
+    }
+
+    let reader = async {
+        while let Some(request) = read.read_frame().await? {
+            match request {
+                AppletRequest::Subscribe(topic) => {
+                    if let Some(payload) = bus.lock().await.subscribe(applet.id, topic.clone()) {
+                        _ = applet
+                            .sender
+                            .send(AppletEvent::Publication { topic, payload });
+                    }
+                }
+                AppletRequest::Unsubscribe(topic) => {
+                    bus.lock().await.unsubscribe(applet.id, &topic)
+                }
+                AppletRequest::Publish { topic, payload } => {
+                    let subscribers = bus.lock().await.publish(applet.id, &topic, payload.clone());
+                    for (subscriber, _) in applets
+                        .lock()
+                        .await
+                        .iter()
+                        .filter(|(a, _)| subscribers.contains(&a.id))
+                    {
+                        _ = subscriber.sender.send(AppletEvent::Publication {
+                            topic: topic.clone(),
+                            payload: payload.clone(),
+                        });
+                    }
+                }
+                AppletRequest::SetVisible(_)
+                | AppletRequest::Tooltip(_)
+                | AppletRequest::Badge(_)
+                | AppletRequest::Urgent(_)
+                | AppletRequest::Menu(_) => {
+                    if let Some(state) = record_state(applets, applet.id, &request).await {
+                        messages.send(RuntimeMessage::State(applet.id, state))?;
+                    }
+                }
+                request => {
+                    record_request(applets, applet.id, &request).await;
+                    messages.send(RuntimeMessage::Request(request, applet.id))?;
                 }
             }
-        });
-    })
+        }
+        Ok(())
+    };
+    let writer = async {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.protocol() > protocol => continue,
+                Ok(AppletEvent::Direction(direction)) => {
+                    context.direction.send_replace(Some(direction));
+                    if applet.info.supports(direction) {
+                        write
+                            .write_frame(&AppletEvent::Direction(direction))
+                            .await?;
+                    } else {
+                        write.write_frame(&AppletEvent::Hide).await?;
+                        messages
+                            .send(RuntimeMessage::UnsupportedDirection(applet.id, direction))?;
+                    }
+                }
+                Ok(event) => write.write_frame(&event).await?,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    };
+    tokio::select! {
+        result = reader => result,
+        result = writer => result,
+    }
 }
 
-pub fn applet_sub() -> impl Stream<Item = AppletMessage> {
-    stream::channel(100, |mut tx: mpsc::Sender<AppletMessage>| async move {
+/// Keeps the runtime's copy of an applet in sync with the requests it sends.
+async fn record_request(applets: &AppletTable, id: u32, request: &AppletRequest) {
+    let mut applets = applets.lock().await;
+    let Some((applet, _)) = applets.iter_mut().find(|(a, _)| a.id == id) else {
+        return;
+    };
+    match request {
+        AppletRequest::Limits(limits) => {
+            applet.limits = Some(*limits);
+            applet.surfaces.entry(None).or_default().limits = Some(*limits);
+        }
+        AppletRequest::Intrinsic(size) => {
+            applet.intrinsic = Some(*size);
+            applet.surfaces.entry(None).or_default().intrinsic = Some(*size);
+        }
+        AppletRequest::OutputLimits { output, limits } => {
+            applet.limits = Some(*limits);
+            applet.surfaces.entry(output.clone()).or_default().limits = Some(*limits);
+        }
+        AppletRequest::OutputIntrinsic { output, size } => {
+            applet.intrinsic = Some(*size);
+            applet.surfaces.entry(output.clone()).or_default().intrinsic = Some(*size);
+        }
+        _ => {}
+    }
+}
+
+/// Applies a request changing the [`AppletState`] of an applet to its record,
+/// returning the new state if it changed.
+async fn record_state(
+    applets: &AppletTable,
+    id: u32,
+    request: &AppletRequest,
+) -> Option<AppletState> {
+    let mut applets = applets.lock().await;
+    let (applet, _) = applets.iter_mut().find(|(a, _)| a.id == id)?;
+    applet.state.apply(request).then(|| applet.state.clone())
+}
+
+type SharedBus = Arc<tokio::sync::Mutex<Bus>>;
+
+pub(crate) type AppletTable =
+    Arc<tokio::sync::Mutex<Vec<(Applet, broadcast::Receiver<AppletEvent>)>>>;
+
+/// Applets that exited and wait to be relaunched, by the id they had.
+pub(crate) type PendingRestarts = Arc<tokio::sync::Mutex<HashMap<u32, PendingRestart>>>;
+
+/// An applet that exited and is relaunched by `task` once its restart delay
+/// has passed.
+pub(crate) struct PendingRestart {
+    pub applet: Applet,
+    /// The number of consecutive relaunches, including this one.
+    pub restarts: u32,
+    task: JoinHandle<()>,
+}
+
+/// How long the runtime waits before accepting connections again after
+/// accepting one failed.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often the runtime checks whether applet processes have exited.
+const REAP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long an applet must stay up for its restart backoff to be reset.
+const STABLE_UPTIME: Duration = Duration::from_secs(30);
+
+/// How long a stopped applet may take to honor [`AppletEvent::Quit`].
+const QUIT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a stopped applet may take to exit after `SIGTERM`.
+const TERM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The state shared by the tasks of a runtime that launch applets.
+#[derive(Clone)]
+struct RuntimeContext {
+    applets: AppletTable,
+    /// The socket launched applets connect to.
+    socket: PathBuf,
+    logs: LogSink,
+    messages: UnboundedSender<RuntimeMessage>,
+    /// The direction the panel faces, learned from the
+    /// [`AppletEvent::Direction`] events the host sends its applets.
+    direction: Arc<watch::Sender<Option<Direction>>>,
+    pending: PendingRestarts,
+}
+
+/// Launches an applet with [`launch_applet`], reporting the new applet or the
+/// failure to launch it to the host.
+async fn spawn_applet(
+    context: &RuntimeContext,
+    info: AppletInfo,
+    order: u32,
+    position: AppletPosition,
+    config: Config,
+    restarts: u32,
+) {
+    let id = info.id.clone();
+    let message = match launch_applet(context, info, order, position, config, restarts).await {
+        Ok(applet) => RuntimeMessage::New(applet),
+        Err(error) => RuntimeMessage::LaunchFailed {
+            id,
+            error: Arc::new(error),
+        },
+    };
+    _ = context.messages.send(message);
+}
+
+/// Launches an applet and registers it in the runtime's applet table.
+async fn launch_applet(
+    context: &RuntimeContext,
+    info: AppletInfo,
+    order: u32,
+    position: AppletPosition,
+    config: Config,
+    restarts: u32,
+) -> Result<Applet, LaunchError> {
+    if let Some(direction) = context.direction.borrow().filter(|d| !info.supports(*d)) {
+        return Err(LaunchError::UnsupportedDirection(direction));
+    }
+    let (applet_sender, applet_receiver) = broadcast::channel(100);
+    let token = generate_token().map_err(LaunchError::Io)?;
+    let mut child = info.launch(&context.socket, &token)?;
+
+    let log = LogBuffer::default();
+    let file = LogFile::new(&info.id).spawn_writer();
+    if let Some(stdout) = child.stdout.take() {
+        runtime().spawn(capture(
+            child.id(),
+            ChildStdout::from_std(stdout).map_err(LaunchError::Io)?,
+            LogStream::Stdout,
+            log.clone(),
+            file.clone(),
+            context.logs.clone(),
+        ));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        runtime().spawn(capture(
+            child.id(),
+            ChildStderr::from_std(stderr).map_err(LaunchError::Io)?,
+            LogStream::Stderr,
+            log.clone(),
+            file,
+            context.logs.clone(),
+        ));
+    }
+
+    let applet = Applet {
+        id: child.id(),
+        order,
+        info,
+        sender: applet_sender,
+        limits: None,
+        intrinsic: None,
+        surfaces: HashMap::new(),
+        size: None,
+        margin: Default::default(),
+        process: Some(Arc::new(Mutex::new(child))),
+        position,
+        restarts,
+        launched_at: Instant::now(),
+        token,
+        log,
+        state: AppletState::default(),
+        config,
+    };
+    context
+        .applets
+        .lock()
+        .await
+        .push((applet.clone(), applet_receiver));
+    Ok(applet)
+}
+
+/// Returns a random secret identifying a single launch of an applet.
+fn generate_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Reloads the running applets whose definition changed on disk.
+async fn reload_on_change(applets: AppletTable, requests: WeakUnboundedSender<RuntimeRequest>) {
+    let mut events = Box::pin(discovery::watch());
+    while let Some(event) = events.next().await {
+        let DiscoveryEvent::Changed(info) = event else {
+            continue;
+        };
+        let Some(requests) = requests.upgrade() else {
+            return;
+        };
+        for (applet, _) in applets.lock().await.iter() {
+            if applet.info.id == info.id {
+                _ = requests.send(RuntimeRequest::Reload(applet.id));
+            }
+        }
+    }
+}
+
+/// Removes an applet from the runtime's applet table.
+async fn take_applet(applets: &AppletTable, id: u32) -> Option<Applet> {
+    let mut applets = applets.lock().await;
+    let index = applets.iter().position(|(a, _)| a.id == id)?;
+    Some(applets.remove(index).0)
+}
+
+/// Cancels the pending relaunch of the applet that had the id `id`, returning
+/// the applet.
+async fn cancel_restart(pending: &PendingRestarts, id: u32) -> Option<Applet> {
+    let restart = pending.lock().await.remove(&id)?;
+    restart.task.abort();
+    Some(restart.applet)
+}
+
+/// Reads the applet definitions with [`AppletInfo::load`], reporting the files
+/// that could not be read to the host.
+pub(crate) fn load_definitions(messages: &UnboundedSender<RuntimeMessage>) -> Vec<AppletInfo> {
+    let definitions = AppletInfo::load();
+    for error in definitions.errors {
+        _ = messages.send(RuntimeMessage::InvalidDefinition(error));
+    }
+    definitions.applets
+}
+
+/// Returns the message reporting that the layout names an applet definition
+/// that does not exist.
+fn unknown_definition(id: &str) -> RuntimeMessage {
+    RuntimeMessage::LaunchFailed {
+        id: id.to_string(),
+        error: Arc::new(LaunchError::UnknownDefinition(id.to_string())),
+    }
+}
+
+/// Spawns a task saving the layouts sent to it to `path`, if the layout has a
+/// file, and reporting failures to the host.
+///
+/// The file is written on a blocking thread, and layouts superseded while an
+/// older one was written are skipped.
+fn layout_writer(
+    path: Option<PathBuf>,
+    messages: UnboundedSender<RuntimeMessage>,
+) -> UnboundedSender<PanelLayout> {
+    let (sender, mut receiver) = unbounded_channel::<PanelLayout>();
+    runtime().spawn(async move {
+        while let Some(mut layout) = receiver.recv().await {
+            while let Ok(newer) = receiver.try_recv() {
+                layout = newer;
+            }
+            let Some(path) = path.clone() else {
+                continue;
+            };
+            let result = tokio::task::spawn_blocking(move || layout.save(&path)).await;
+            if let Ok(Err(error)) = result {
+                _ = messages.send(RuntimeMessage::LayoutError(Arc::new(error)));
+            }
+        }
+    });
+    sender
+}
+
+/// Polls an applet process until it exits or `timeout` elapses, returning
+/// its exit status if the runtime launched it.
+async fn wait_for_exit(applet: &Applet, timeout: Duration) -> Option<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match &applet.process {
+            Some(process) => {
+                if let Ok(Some(status)) = lock(process).try_wait() {
+                    return Some(Some(status));
+                }
+            }
+            None => {
+                if unsafe { libc::kill(applet.id as libc::pid_t, 0) } != 0
+                    && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+                {
+                    return Some(None);
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(REAP_INTERVAL).await;
+    }
+}
+
+/// Asks an applet to quit, escalating to `SIGTERM` and then `SIGKILL` if it
+/// is still running after [`QUIT_TIMEOUT`] and [`TERM_TIMEOUT`] respectively,
+/// and reports it to the host as shut down, or as detached if it was adopted.
+async fn stop_applet(applet: &Applet, messages: &UnboundedSender<RuntimeMessage>) {
+    let message = |status: Option<ExitStatus>| match status {
+        Some(status) => RuntimeMessage::Shutdown(applet.id, status),
+        None => RuntimeMessage::Detached(applet.id),
+    };
+
+    _ = applet.sender.send(AppletEvent::Quit);
+    if let Some(status) = wait_for_exit(applet, QUIT_TIMEOUT).await {
+        _ = messages.send(message(status));
+        return;
+    }
+
+    unsafe {
+        libc::kill(applet.id as libc::pid_t, libc::SIGTERM);
+    }
+    if let Some(status) = wait_for_exit(applet, TERM_TIMEOUT).await {
+        _ = messages.send(message(status));
+        return;
+    }
+
+    match &applet.process {
+        Some(process) => _ = lock(process).kill(),
+        None => unsafe {
+            libc::kill(applet.id as libc::pid_t, libc::SIGKILL);
+        },
+    }
+    // A killed process exits as soon as the kernel gets to it.
+    loop {
+        if let Some(status) = wait_for_exit(applet, TERM_TIMEOUT).await {
+            _ = messages.send(message(status));
+            return;
+        }
+    }
+}
+
+/// Locks an applet process, which stays usable even if a thread panicked
+/// while holding the lock.
+fn lock(process: &Mutex<Child>) -> MutexGuard<'_, Child> {
+    process.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn applet_sub(policy: DisconnectPolicy) -> impl Stream<Item = AppletMessage> {
+    stream::channel(100, move |mut tx: mpsc::Sender<AppletMessage>| async move {
         let (sender, mut receiver) = unbounded_channel();
         let (stream_sender, mut stream_receiver) = unbounded_channel();
 
-        tx.send(AppletMessage::Ready(sender)).await.unwrap();
+        if tx.send(AppletMessage::Ready(sender)).await.is_err() {
+            return;
+        }
         runtime().spawn(async move {
-            let socket = Path::new("/tmp/sock");
-            if let Ok(stream) = UnixStream::connect(socket).await {
-                let (mut read, mut write) = stream.into_split();
-                let mut connection = BufConnection::new();
-                runtime().spawn(async move {
-                    loop {
-                        if let Ok(Some(event)) = connection.read_frame(&mut read).await {
-                            stream_sender.send(AppletMessage::Event(event)).unwrap();
-                        };
-                    }
-                });
-                runtime().spawn(async move {
-                    loop {
-                        if let Some(request) = receiver.recv().await {
-                            write_frame(request, &mut write).await.unwrap();
+            let mut replay = Replay::default();
+            let mut delay = RECONNECT_MIN_DELAY;
+            loop {
+                let error = match connect_applet(&replay, &stream_sender).await {
+                    Ok(Some((framed, protocol))) => {
+                        delay = RECONNECT_MIN_DELAY;
+                        let error = relay_applet(
+                            framed,
+                            protocol,
+                            &mut receiver,
+                            &stream_sender,
+                            &mut replay,
+                        )
+                        .await;
+                        if policy == DisconnectPolicy::Exit {
+                            _ = stream_sender.send(AppletMessage::Disconnected(Arc::new(error)));
+                            return;
                         }
+                        error
                     }
-                });
+                    Ok(None) => return,
+                    Err(ConnectionError::UnknownApplet) if policy == DisconnectPolicy::Exit => {
+                        _ = stream_sender.send(AppletMessage::Rejected(
+                            RejectReason::UnknownApplet.to_string(),
+                        ));
+                        return;
+                    }
+                    Err(error) => error,
+                };
+                if stream_sender
+                    .send(AppletMessage::Disconnected(Arc::new(error)))
+                    .is_err()
+                    || stream_sender
+                        .send(AppletMessage::Reconnecting(delay))
+                        .is_err()
+                {
+                    return;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
             }
         });
 
-        runtime().spawn(async move {
-            loop {
-                if let Some(message) = stream_receiver.recv().await {
-                    tx.send(message).await.unwrap();
-                }
+        while let Some(message) = stream_receiver.recv().await {
+            if tx.send(message).await.is_err() {
+                break;
             }
-        });
+        }
     })
 }
 
-pub struct BufConnection {
-    buffer: BytesMut,
+/// How long an applet waits before its first attempt to connect again. The
+/// delay doubles with every failed attempt, up to [`RECONNECT_MAX_DELAY`].
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The requests describing the state of an applet, sent again whenever it
+/// connects to a new runtime.
+#[derive(Default)]
+struct Replay {
+    /// The limits and intrinsic sizes of the applet's surfaces, keyed by
+    /// output as in [`AppletRequest::OutputLimits`].
+    limits: HashMap<Option<String>, Limits>,
+    intrinsic: HashMap<Option<String>, Size>,
+    topics: HashSet<String>,
+    state: AppletState,
 }
 
-impl BufConnection {
-    pub fn new() -> Self {
-        Self {
-            buffer: BytesMut::with_capacity(4096),
+impl Replay {
+    fn record(&mut self, request: &AppletRequest) {
+        match request {
+            AppletRequest::Limits(limits) => {
+                self.limits.insert(None, *limits);
+            }
+            AppletRequest::Intrinsic(size) => {
+                self.intrinsic.insert(None, *size);
+            }
+            AppletRequest::OutputLimits { output, limits } => {
+                self.limits.insert(output.clone(), *limits);
+            }
+            AppletRequest::OutputIntrinsic { output, size } => {
+                self.intrinsic.insert(output.clone(), *size);
+            }
+            AppletRequest::Subscribe(topic) => {
+                self.topics.insert(topic.clone());
+            }
+            AppletRequest::Unsubscribe(topic) => {
+                self.topics.remove(topic);
+            }
+            request => {
+                self.state.apply(request);
+            }
         }
     }
 
-    pub async fn read_frame<T, R>(&mut self, reader: &mut R) -> Result<Option<T>, Box<dyn Error>>
-    where
-        T: Serialize + DeserializeOwned,
-        R: AsyncReadExt + Unpin,
+    fn requests(&self) -> impl Iterator<Item = AppletRequest> + '_ {
+        let limits = self
+            .limits
+            .iter()
+            .map(|(output, limits)| AppletRequest::OutputLimits {
+                output: output.clone(),
+                limits: *limits,
+            });
+        let intrinsic =
+            self.intrinsic
+                .iter()
+                .map(|(output, size)| AppletRequest::OutputIntrinsic {
+                    output: output.clone(),
+                    size: *size,
+                });
+        limits
+            .chain(intrinsic)
+            .chain(self.topics.iter().cloned().map(AppletRequest::Subscribe))
+            .chain(self.state.requests())
+    }
+}
+
+/// Connects to the runtime and performs the handshake, returning the
+/// connection and the negotiated protocol, or `None` if the runtime rejected
+/// the applet.
+async fn connect_applet(
+    replay: &Replay,
+    messages: &UnboundedSender<AppletMessage>,
+) -> Result<Option<(Framed<UnixStream>, u32)>, ConnectionError> {
+    let stream = UnixStream::connect(applet_socket_path()?).await?;
+    let mut framed = Framed::with_format(stream, WireFormat::from_env());
+
+    let hello = Hello {
+        token: env::var(TOKEN_ENV).ok(),
+        applet: env::var(APPLET_ENV).ok(),
+        ..Hello::current()
+    };
+    framed.write_frame(&hello).await?;
+    match framed
+        .read_frame::<Handshake>()
+        .await?
+        .ok_or(ConnectionError::Handshake)?
     {
-        loop {
-            if let Ok(frame) = self.parse_frame(reader).await {
-                return Ok(frame);
+        Handshake::Accepted(hello) => {
+            if let Err(reason) = Hello::current().negotiate(&hello) {
+                messages.send(AppletMessage::Rejected(reason))?;
+                return Ok(None);
             }
-
-            if 0 == reader.read_buf(&mut self.buffer).await? {
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err("connection reset by peer".into());
-                }
+            let protocol = hello.protocol;
+            for request in replay.requests().filter_map(|r| r.for_protocol(protocol)) {
+                framed.write_frame(&request).await?;
             }
+            messages.send(AppletMessage::Connected(hello))?;
+            Ok(Some((framed, protocol)))
+        }
+        Handshake::Rejected(RejectReason::UnknownApplet) => Err(ConnectionError::UnknownApplet),
+        Handshake::Rejected(RejectReason::Incompatible(reason)) => {
+            messages.send(AppletMessage::Rejected(reason))?;
+            Ok(None)
         }
     }
+}
 
-    async fn parse_frame<T, R>(&mut self, reader: &mut R) -> Result<Option<T>, Box<dyn Error>>
-    where
-        T: Serialize + DeserializeOwned,
-        R: AsyncReadExt + Unpin,
-    {
-        if self.buffer.has_remaining() {
-            let size = (&self.buffer[0..4]).get_u32_le() as usize;
-            if self.buffer.remaining() - 4 < size {
-                return Err("Size doesn't match actual buffer size".into());
+/// Relays the applet's requests to the runtime and the runtime's events to the
+/// applet until the connection is lost, dropping requests the runtime does
+/// not know in the negotiated `protocol`.
+async fn relay_applet(
+    framed: Framed<UnixStream>,
+    protocol: u32,
+    requests: &mut tokio::sync::mpsc::UnboundedReceiver<AppletRequest>,
+    messages: &UnboundedSender<AppletMessage>,
+    replay: &mut Replay,
+) -> ConnectionError {
+    let (mut read, mut write) = framed.into_split();
+    let reader = async {
+        while let Some(event) = read.read_frame().await? {
+            messages.send(AppletMessage::Event(event))?;
+        }
+        Err(ConnectionError::Eof)
+    };
+    let writer = async {
+        while let Some(request) = requests.recv().await {
+            replay.record(&request);
+            if let Some(request) = request.for_protocol(protocol) {
+                write.write_frame(&request).await?;
             }
-            let element: T = bincode::serde::decode_from_slice(
-                &self.buffer[4..size + 4],
-                bincode::config::standard(),
-            )
-            .unwrap()
-            .0;
-            self.buffer.advance(size + 4);
-            return Ok(Some(element));
         }
+        Err(ConnectionError::Closed)
+    };
+    let result: Result<(), ConnectionError> = tokio::select! {
+        result = reader => result,
+        result = writer => result,
+    };
+    result.err().unwrap_or(ConnectionError::Closed)
+}
 
-        let mut size_buf = vec![0; 4];
-        let _ = reader.read_exact(&mut size_buf).await?;
+/// A connection carrying frames in a [`WireFormat`] over a byte stream.
+///
+/// Reading is cancel safe and copes with frames split across reads as well as
+/// several frames arriving in a single read.
+pub struct Framed<S> {
+    io: S,
+    format: WireFormat,
+    read_buffer: BytesMut,
+    /// The bytes at the start of `read_buffer` known not to end a frame.
+    scanned: usize,
+    write_buffer: BytesMut,
+}
 
-        let size = size_buf.as_slice().get_u32_le() as usize;
+impl<S> Framed<S> {
+    pub fn new(io: S) -> Self {
+        Self::with_format(io, WireFormat::default())
+    }
 
-        if size >= self.buffer.remaining() {
-            self.buffer.reserve(4096);
-        }
-        let num_bytes = reader.read_buf(&mut self.buffer).await?;
-        if size > num_bytes {
-            return Err("Buffer size smaller than frame size".into());
+    pub fn with_format(io: S, format: WireFormat) -> Self {
+        Self {
+            io,
+            format,
+            read_buffer: BytesMut::with_capacity(4096),
+            scanned: 0,
+            write_buffer: BytesMut::with_capacity(4096),
         }
+    }
 
-        let element: T =
-            bincode::serde::decode_from_slice(&self.buffer[0..size], bincode::config::standard())?
-                .0;
-        self.buffer.advance(size);
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
 
-        Ok(Some(element))
+    pub fn get_ref(&self) -> &S {
+        &self.io
     }
-}
 
-pub async fn write_frame<T, W>(message: T, writer: &mut W) -> Result<(), Box<dyn Error>>
-where
-    T: Serialize + DeserializeOwned,
-    W: AsyncWriteExt + Unpin,
-{
-    let mut src = bincode::serde::encode_to_vec(message, bincode::config::standard())?;
-    let len = (src.len() as u32).to_le_bytes();
-    src.splice(0..0, len);
-    writer.write_all(&mut src).await?;
-    writer.flush().await?;
-    Ok(())
+    pub fn into_inner(self) -> S {
+        self.io
+    }
 }
 
-pub struct Connection {
-    stream: UnixStream,
-    buffer: BytesMut,
+impl Framed<UnixStream> {
+    /// Splits the connection into a reading and a writing half, keeping any
+    /// bytes that were already received.
+    pub fn into_split(self) -> (Framed<OwnedReadHalf>, Framed<OwnedWriteHalf>) {
+        let (read, write) = self.io.into_split();
+        (
+            Framed {
+                io: read,
+                format: self.format,
+                read_buffer: self.read_buffer,
+                scanned: self.scanned,
+                write_buffer: BytesMut::new(),
+            },
+            Framed {
+                io: write,
+                format: self.format,
+                read_buffer: BytesMut::new(),
+                scanned: 0,
+                write_buffer: self.write_buffer,
+            },
+        )
+    }
 }
 
-impl Connection {
-    pub fn new(stream: UnixStream) -> Self {
-        Self {
-            stream,
-            buffer: BytesMut::with_capacity(4096),
+impl<S: AsyncRead + Unpin> Framed<S> {
+    /// Waits for the peer's first bytes and adopts the [`WireFormat`] they
+    /// are written in.
+    pub async fn detect_format(&mut self) -> Result<WireFormat, ConnectionError> {
+        while self.read_buffer.len() < 4 {
+            if 0 == self.io.read_buf(&mut self.read_buffer).await? {
+                return Err(ConnectionError::Handshake);
+            }
         }
+        self.format = WireFormat::detect(&self.read_buffer);
+        Ok(self.format)
     }
 
-    pub async fn read_frame<T>(&mut self) -> Result<Option<T>, Box<dyn Error>>
+    /// Reads the next frame, returning `None` once the peer closed the
+    /// connection cleanly.
+    pub async fn read_frame<T>(&mut self) -> Result<Option<T>, ConnectionError>
     where
-        T: Serialize + DeserializeOwned,
+        T: DeserializeOwned,
     {
         loop {
-            if let Ok(frame) = self.parse_frame().await {
-                return Ok(frame);
+            if let Some(frame) = self
+                .format
+                .decode_from(&mut self.read_buffer, &mut self.scanned)?
+            {
+                return Ok(Some(frame));
             }
 
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                if self.buffer.is_empty() {
+            if 0 == self.io.read_buf(&mut self.read_buffer).await? {
+                if self.read_buffer.is_empty() {
                     return Ok(None);
                 } else {
-                    return Err("connection reset by peer".into());
+                    return Err(ConnectionError::Reset);
                 }
             }
         }
     }
+}
 
-    async fn parse_frame<T>(&mut self) -> Result<Option<T>, Box<dyn Error>>
+impl<S: AsyncWrite + Unpin> Framed<S> {
+    pub async fn write_frame<T>(&mut self, message: &T) -> Result<(), ConnectionError>
     where
-        T: Serialize + DeserializeOwned,
+        T: Serialize,
     {
-        if !self.buffer.is_empty() {
-            let size = (&self.buffer[0..4]).get_u32_le() as usize;
-            if self.buffer[5..].len() < size {
-                return Err("Size doesn't match actual buffer size".into());
+        self.format.encode(message, &mut self.write_buffer)?;
+        self.io.write_all_buf(&mut self.write_buffer).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+}
+
+fn encode_bincode<T>(message: &T, dst: &mut BytesMut) -> Result<(), ConnectionError>
+where
+    T: Serialize,
+{
+    let start = dst.len();
+    dst.put_u32_le(0);
+    let size = match bincode::serde::encode_into_std_write(
+        message,
+        &mut (&mut *dst).writer(),
+        bincode::config::standard(),
+    ) {
+        Ok(size) if size <= MAX_FRAME_SIZE => size,
+        Ok(size) => {
+            dst.truncate(start);
+            return Err(ConnectionError::FrameTooLarge(size));
+        }
+        Err(error) => {
+            dst.truncate(start);
+            return Err(error.into());
+        }
+    };
+    dst[start..start + 4].copy_from_slice(&(size as u32).to_le_bytes());
+    Ok(())
+}
+
+fn decode_bincode<T>(src: &mut BytesMut) -> Result<Option<T>, ConnectionError>
+where
+    T: DeserializeOwned,
+{
+    if src.len() < 4 {
+        return Ok(None);
+    }
+    let size = (&src[0..4]).get_u32_le() as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(ConnectionError::FrameTooLarge(size));
+    }
+    if src.len() < 4 + size {
+        src.reserve(4 + size - src.len());
+        return Ok(None);
+    }
+
+    let (element, _) =
+        bincode::serde::decode_from_slice(&src[4..4 + size], bincode::config::standard())?;
+    src.advance(4 + size);
+    Ok(Some(element))
+}
+
+fn encode_json<T>(message: &T, dst: &mut BytesMut) -> Result<(), ConnectionError>
+where
+    T: Serialize,
+{
+    let start = dst.len();
+    if let Err(error) = serde_json::to_writer((&mut *dst).writer(), message) {
+        dst.truncate(start);
+        return Err(error.into());
+    }
+    let size = dst.len() - start;
+    if size > MAX_FRAME_SIZE {
+        dst.truncate(start);
+        return Err(ConnectionError::FrameTooLarge(size));
+    }
+    dst.put_u8(b'\n');
+    Ok(())
+}
+
+fn decode_json<T>(src: &mut BytesMut, scanned: &mut usize) -> Result<Option<T>, ConnectionError>
+where
+    T: DeserializeOwned,
+{
+    loop {
+        let Some(end) = src[*scanned..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map(|end| *scanned + end)
+        else {
+            *scanned = src.len();
+            if src.len() > MAX_FRAME_SIZE {
+                return Err(ConnectionError::FrameTooLarge(src.len()));
             }
+            return Ok(None);
+        };
+        *scanned = 0;
+        if end > MAX_FRAME_SIZE {
+            return Err(ConnectionError::FrameTooLarge(end));
+        }
 
-            let element: T = bincode::serde::decode_from_slice(
-                &self.buffer[5..size],
-                bincode::config::standard(),
-            )?
-            .0;
-            self.buffer.advance(4 + size);
-            return Ok(Some(element));
+        let line = src.split_to(end + 1);
+        let line = line[..end].trim_ascii();
+        if !line.is_empty() {
+            return Ok(Some(serde_json::from_slice(line)?));
         }
-        let mut size_buf = vec![0; 4];
-        let _ = self.stream.read_exact(&mut size_buf).await?;
+    }
+}
 
-        let size = size_buf.as_slice().get_u32_le();
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tokio::io::{DuplexStream, duplex};
 
-        if size as usize >= self.buffer.remaining() {
-            self.buffer.reserve(size as usize + 4);
+    use super::*;
+    use crate::applet::interface::PROTOCOL_VERSION;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Frame {
+        id: u32,
+        text: String,
+    }
+
+    fn frame(id: u32) -> Frame {
+        Frame {
+            id,
+            text: format!("frame {id}"),
         }
-        let num_bytes = self.stream.read_buf(&mut self.buffer).await?;
+    }
+
+    const FORMATS: [WireFormat; 2] = [WireFormat::Bincode, WireFormat::Json];
 
-        if size != num_bytes as u32 {
-            return Err("Size doesn't match actual buffer size".into());
+    fn encode(format: WireFormat, frames: impl IntoIterator<Item = Frame>) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for frame in frames {
+            format.encode(&frame, &mut bytes).unwrap();
         }
+        bytes
+    }
 
-        let element: T =
-            bincode::serde::decode_from_slice(&self.buffer, bincode::config::standard())?.0;
-        self.buffer.advance(num_bytes);
+    /// Returns a reader for `bytes`, which a task writes into a pipe holding
+    /// at most `capacity` bytes at a time before closing it.
+    fn reader(format: WireFormat, bytes: Vec<u8>, capacity: usize) -> Framed<DuplexStream> {
+        let (mut write, read) = duplex(capacity);
+        tokio::spawn(async move { _ = write.write_all(&bytes).await });
+        Framed::with_format(read, format)
+    }
 
-        Ok(Some(element))
+    #[tokio::test]
+    async fn round_trip() {
+        for format in FORMATS {
+            let (write, read) = duplex(4096);
+            let mut write = Framed::with_format(write, format);
+            let mut read = Framed::with_format(read, format);
+            write.write_frame(&frame(1)).await.unwrap();
+            write.write_frame(&frame(2)).await.unwrap();
+            drop(write);
+            assert_eq!(read.read_frame().await.unwrap(), Some(frame(1)));
+            assert_eq!(read.read_frame().await.unwrap(), Some(frame(2)));
+            assert_eq!(read.read_frame::<Frame>().await.unwrap(), None);
+        }
     }
 
-    pub async fn write_frame<T>(&mut self, message: T) -> Result<(), Box<dyn Error>>
-    where
-        T: Serialize + DeserializeOwned,
-    {
-        let mut src = bincode::serde::encode_to_vec(message, bincode::config::standard())?;
-        let len = (src.len() as u32).to_le_bytes();
-        src.splice(0..0, len);
-        self.stream.write_all(&mut src).await?;
-        self.stream.flush().await?;
-        Ok(())
+    #[tokio::test]
+    async fn frame_split_across_reads() {
+        for format in FORMATS {
+            let bytes = encode(format, [frame(1)]).to_vec();
+            let mut read = reader(format, bytes, 1);
+            assert_eq!(read.read_frame().await.unwrap(), Some(frame(1)));
+            assert_eq!(read.read_frame::<Frame>().await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_in_one_read() {
+        for format in FORMATS {
+            let bytes = encode(format, (1..=3).map(frame)).to_vec();
+            let mut read = reader(format, bytes, 4096);
+            for id in 1..=3 {
+                assert_eq!(read.read_frame().await.unwrap(), Some(frame(id)));
+            }
+            assert_eq!(read.read_frame::<Frame>().await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_too_large() {
+        let size = MAX_FRAME_SIZE + 1;
+        let mut read = reader(
+            WireFormat::Bincode,
+            (size as u32).to_le_bytes().to_vec(),
+            4096,
+        );
+        assert!(matches!(
+            read.read_frame::<Frame>().await,
+            Err(ConnectionError::FrameTooLarge(s)) if s == size
+        ));
+
+        let mut read = reader(WireFormat::Json, vec![b' '; size], 4096);
+        assert!(matches!(
+            read.read_frame::<Frame>().await,
+            Err(ConnectionError::FrameTooLarge(s)) if s > MAX_FRAME_SIZE
+        ));
+    }
+
+    #[tokio::test]
+    async fn eof_in_frame() {
+        for format in FORMATS {
+            let mut bytes = encode(format, [frame(1)]).to_vec();
+            bytes.pop();
+            let mut read = reader(format, bytes, 4096);
+            assert!(matches!(
+                read.read_frame::<Frame>().await,
+                Err(ConnectionError::Reset)
+            ));
+        }
+    }
+
+    #[test]
+    fn detect() {
+        for format in FORMATS {
+            let bytes = encode(format, [frame(1)]);
+            assert_eq!(WireFormat::detect(&bytes), format);
+        }
+        let mut unit = BytesMut::new();
+        WireFormat::Json
+            .encode(&AppletEvent::Show, &mut unit)
+            .unwrap();
+        assert_eq!(WireFormat::detect(&unit), WireFormat::Json);
+
+        // Bincode frames whose length starts with `{` or `"`.
+        for len in [b'{', b'"'] {
+            let mut bytes = encode(WireFormat::Bincode, [frame(1)]);
+            bytes[..4].copy_from_slice(&u32::from(len).to_le_bytes());
+            assert_eq!(WireFormat::detect(&bytes), WireFormat::Bincode);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_frame_in_many_reads() {
+        let mut bytes = encode(WireFormat::Json, [frame(1), frame(2)]).to_vec();
+        bytes.splice(1..1, b" ".repeat(2000));
+        let mut read = reader(WireFormat::Json, bytes, 7);
+        assert_eq!(read.read_frame().await.unwrap(), Some(frame(1)));
+        assert_eq!(read.read_frame().await.unwrap(), Some(frame(2)));
+        assert_eq!(read.read_frame::<Frame>().await.unwrap(), None);
+    }
+
+    #[test]
+    fn replay_per_output() {
+        let mut replay = Replay::default();
+        for output in ["DP-1", "HDMI-A-1", "DP-1"] {
+            replay.record(&AppletRequest::OutputIntrinsic {
+                output: Some(output.to_string()),
+                size: Size::new(32., 32.),
+            });
+        }
+
+        let current: Vec<_> = replay
+            .requests()
+            .filter_map(|r| r.for_protocol(PROTOCOL_VERSION))
+            .collect();
+        assert_eq!(current.len(), 2);
+        assert!(
+            current
+                .iter()
+                .all(|r| matches!(r, AppletRequest::OutputIntrinsic { .. }))
+        );
+
+        let v1: Vec<_> = replay
+            .requests()
+            .filter_map(|r| r.for_protocol(1))
+            .collect();
+        assert_eq!(v1.len(), 2);
+        assert!(v1.iter().all(|r| matches!(r, AppletRequest::Intrinsic(_))));
     }
 }
@@ -0,0 +1,253 @@
+use std::{io, path::PathBuf, time::Duration};
+
+use iced::{Size, advanced::layout::Limits};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::mpsc::{UnboundedSender, WeakUnboundedSender},
+};
+
+use crate::{
+    applet::{
+        connection::{
+            AppletTable, ConnectionError, Framed, PendingRestarts, WireFormat, accept,
+            load_definitions, socket_path,
+        },
+        interface::{
+            Applet, AppletEvent, AppletPosition, AppletState, RuntimeMessage, RuntimeRequest,
+        },
+        log::LogLine,
+        serde_types::{option_limits, option_size},
+    },
+    runtime,
+};
+
+/// Returns the control socket path of the runtime named `instance`, which is
+/// `$XDG_RUNTIME_DIR/azalea/<instance>.ctl`.
+pub fn control_socket_path(instance: &str) -> io::Result<PathBuf> {
+    Ok(socket_path(instance)?.with_extension("ctl"))
+}
+
+const SHUTTING_DOWN: &str = "runtime is shutting down";
+
+/// A command sent to a running runtime through its control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlRequest {
+    /// Lists the applets the runtime launched.
+    List,
+    /// Launches the applet definition with the given id, at its default
+    /// position unless one is given.
+    Launch {
+        id: String,
+        position: Option<AppletPosition>,
+    },
+    Stop(u32),
+    Restart(u32),
+    /// Sends an event to an applet as if it came from the host.
+    Event(u32, AppletEvent),
+    /// Returns the limits an applet requested.
+    Limits(u32),
+    /// Returns the buffered output of an applet.
+    Log(u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Ok,
+    Applets(Vec<AppletStatus>),
+    Limits(#[serde(with = "option_limits")] Option<Limits>),
+    Log(Vec<LogLine>),
+    Error(String),
+}
+
+/// A snapshot of an applet in the runtime's applet table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppletStatus {
+    pub pid: u32,
+    pub id: String,
+    pub name: String,
+    pub position: AppletPosition,
+    pub order: u32,
+    pub restarts: u32,
+    pub uptime: Duration,
+    #[serde(with = "option_limits")]
+    pub limits: Option<Limits>,
+    #[serde(with = "option_size")]
+    pub intrinsic: Option<Size>,
+    pub state: AppletState,
+    /// Whether the applet exited and waits to be relaunched.
+    #[serde(default)]
+    pub restarting: bool,
+}
+
+impl From<&Applet> for AppletStatus {
+    fn from(applet: &Applet) -> Self {
+        Self {
+            pid: applet.id,
+            id: applet.info.id.clone(),
+            name: applet.info.name.clone(),
+            position: applet.position.clone(),
+            order: applet.order,
+            restarts: applet.restarts,
+            uptime: applet.launched_at.elapsed(),
+            limits: applet.limits,
+            intrinsic: applet.intrinsic,
+            state: applet.state.clone(),
+            restarting: false,
+        }
+    }
+}
+
+/// A connection to the control socket of a running runtime.
+pub struct ControlClient {
+    framed: Framed<UnixStream>,
+}
+
+impl ControlClient {
+    /// Connects to the runtime named `instance`.
+    pub async fn connect(instance: &str) -> Result<Self, ConnectionError> {
+        let stream = UnixStream::connect(control_socket_path(instance)?).await?;
+        Ok(Self {
+            framed: Framed::with_format(stream, WireFormat::from_env()),
+        })
+    }
+
+    pub async fn request(
+        &mut self,
+        request: &ControlRequest,
+    ) -> Result<ControlResponse, ConnectionError> {
+        self.framed.write_frame(request).await?;
+        self.framed
+            .read_frame()
+            .await?
+            .ok_or(ConnectionError::Reset)
+    }
+}
+
+/// Accepts control connections until the runtime shuts down.
+pub(crate) async fn serve_control(
+    listener: UnixListener,
+    applets: AppletTable,
+    pending: PendingRestarts,
+    requests: WeakUnboundedSender<RuntimeRequest>,
+    messages: UnboundedSender<RuntimeMessage>,
+) {
+    while requests.strong_count() > 0 {
+        let stream = accept(&listener, &messages).await;
+        let applets = applets.clone();
+        let pending = pending.clone();
+        let requests = requests.clone();
+        let messages = messages.clone();
+        runtime().spawn(async move {
+            _ = serve_client(stream, &applets, &pending, &requests, &messages).await;
+        });
+    }
+}
+
+async fn serve_client(
+    stream: UnixStream,
+    applets: &AppletTable,
+    pending: &PendingRestarts,
+    requests: &WeakUnboundedSender<RuntimeRequest>,
+    messages: &UnboundedSender<RuntimeMessage>,
+) -> Result<(), ConnectionError> {
+    let mut framed = Framed::new(stream);
+    framed.detect_format().await?;
+    while let Some(request) = framed.read_frame().await? {
+        let response = handle_request(request, applets, pending, requests, messages).await;
+        framed.write_frame(&response).await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    request: ControlRequest,
+    applets: &AppletTable,
+    pending: &PendingRestarts,
+    requests: &WeakUnboundedSender<RuntimeRequest>,
+    messages: &UnboundedSender<RuntimeMessage>,
+) -> ControlResponse {
+    let Some(requests) = requests.upgrade() else {
+        return ControlResponse::Error(SHUTTING_DOWN.to_string());
+    };
+    let applets = applets.lock().await;
+    let pending = pending.lock().await;
+    let find = |pid: u32| {
+        applets
+            .iter()
+            .map(|(applet, _)| applet)
+            .find(|applet| applet.id == pid)
+            .ok_or_else(|| ControlResponse::Error(format!("no applet with pid {pid}")))
+    };
+
+    let result = match request {
+        ControlRequest::List => {
+            let mut status: Vec<_> = applets.iter().map(|(a, _)| AppletStatus::from(a)).collect();
+            status.extend(pending.values().map(|restart| AppletStatus {
+                restarts: restart.restarts,
+                restarting: true,
+                ..AppletStatus::from(&restart.applet)
+            }));
+            status.sort_by(|a, b| (&a.position, a.order).cmp(&(&b.position, b.order)));
+            return ControlResponse::Applets(status);
+        }
+        ControlRequest::Launch { id, position } => {
+            let Some(info) = load_definitions(messages)
+                .into_iter()
+                .find(|info| info.id == id)
+            else {
+                return ControlResponse::Error(format!("no applet definition with id {id}"));
+            };
+            let position = position
+                .or_else(|| info.default_position.clone())
+                .unwrap_or(AppletPosition::End);
+            let order = info.default_order.unwrap_or_else(|| {
+                applets
+                    .iter()
+                    .filter(|(a, _)| a.position == position)
+                    .count() as u32
+            });
+            requests.send(RuntimeRequest::Launch {
+                info,
+                order,
+                position,
+            })
+        }
+        ControlRequest::Stop(pid) => match find(pid) {
+            Ok(_) => requests.send(RuntimeRequest::Stop(pid)),
+            Err(_) if pending.contains_key(&pid) => requests.send(RuntimeRequest::Stop(pid)),
+            Err(response) => return response,
+        },
+        ControlRequest::Restart(pid) => match find(pid) {
+            Ok(_) => requests.send(RuntimeRequest::Restart(pid)),
+            Err(_) if pending.contains_key(&pid) => requests.send(RuntimeRequest::Restart(pid)),
+            Err(response) => return response,
+        },
+        ControlRequest::Event(pid, event) => {
+            return match find(pid) {
+                Ok(applet) => {
+                    _ = applet.sender.send(event);
+                    ControlResponse::Ok
+                }
+                Err(response) => response,
+            };
+        }
+        ControlRequest::Limits(pid) => {
+            return match find(pid) {
+                Ok(applet) => ControlResponse::Limits(applet.limits),
+                Err(response) => response,
+            };
+        }
+        ControlRequest::Log(pid) => {
+            return match find(pid) {
+                Ok(applet) => ControlResponse::Log(applet.log.lines()),
+                Err(response) => response,
+            };
+        }
+    };
+
+    match result {
+        Ok(()) => ControlResponse::Ok,
+        Err(_) => ControlResponse::Error(SHUTTING_DOWN.to_string()),
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use iced::{
+    futures::{SinkExt, Stream, channel::mpsc},
+    stream,
+};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::{
+    applet::interface::{AppletInfo, DefinitionError, Definitions},
+    runtime,
+};
+
+/// A change to the applet definitions installed on disk.
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    Added(AppletInfo),
+    Changed(AppletInfo),
+    Removed(AppletInfo),
+    /// A definition file became unreadable or invalid.
+    Invalid(DefinitionError),
+}
+
+/// How long to wait for a burst of file system events to settle before
+/// rescanning the applet directories.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watches the applet directories, yielding an event whenever a definition is
+/// added, changed or removed.
+pub fn watch() -> impl Stream<Item = DiscoveryEvent> {
+    stream::channel(100, |mut tx: mpsc::Sender<DiscoveryEvent>| async move {
+        let (stream_sender, mut stream_receiver) = unbounded_channel();
+
+        runtime().spawn(async move {
+            let (sender, mut receiver) = unbounded_channel();
+            let Ok(mut watcher) =
+                notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                    if event.is_ok_and(|event| !event.kind.is_access()) {
+                        _ = sender.send(());
+                    }
+                })
+            else {
+                return;
+            };
+            let mut watched = HashSet::new();
+            rewatch(&mut watcher, &mut watched);
+
+            let mut known = Known::from(AppletInfo::load());
+            loop {
+                tokio::select! {
+                    event = receiver.recv() => if event.is_none() {
+                        return;
+                    },
+                    _ = stream_sender.closed() => return,
+                }
+                tokio::time::sleep(DEBOUNCE).await;
+                while receiver.try_recv().is_ok() {}
+                rewatch(&mut watcher, &mut watched);
+
+                let current = Known::from(AppletInfo::load());
+                for event in known.diff(&current) {
+                    if stream_sender.send(event).is_err() {
+                        return;
+                    }
+                }
+                known = current;
+            }
+        });
+
+        while let Some(event) = stream_receiver.recv().await {
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Watches every applet directory, or the nearest existing ancestor of those
+/// that do not exist yet so that they are picked up once they are created.
+fn rewatch(watcher: &mut impl Watcher, watched: &mut HashSet<PathBuf>) {
+    let wanted: HashSet<PathBuf> = AppletInfo::dirs()
+        .iter()
+        .filter_map(|dir| dir.ancestors().find(|path| path.is_dir()))
+        .map(PathBuf::from)
+        .collect();
+    for path in watched.difference(&wanted) {
+        _ = watcher.unwatch(path);
+    }
+    for path in wanted.difference(watched) {
+        _ = watcher.watch(path, RecursiveMode::NonRecursive);
+    }
+    *watched = wanted;
+}
+
+/// The definitions and invalid files seen by the last scan.
+struct Known {
+    applets: HashMap<String, AppletInfo>,
+    errors: HashMap<PathBuf, DefinitionError>,
+}
+
+impl From<Definitions> for Known {
+    fn from(definitions: Definitions) -> Self {
+        Self {
+            applets: definitions
+                .applets
+                .into_iter()
+                .map(|info| (info.id.clone(), info))
+                .collect(),
+            errors: definitions
+                .errors
+                .into_iter()
+                .map(|error| (error.path.clone(), error))
+                .collect(),
+        }
+    }
+}
+
+impl Known {
+    fn diff(&self, new: &Known) -> Vec<DiscoveryEvent> {
+        let removed = self
+            .applets
+            .iter()
+            .filter(|(id, _)| !new.applets.contains_key(*id))
+            .map(|(_, info)| DiscoveryEvent::Removed(info.clone()));
+        let added_or_changed =
+            new.applets
+                .iter()
+                .filter_map(|(id, info)| match self.applets.get(id) {
+                    None => Some(DiscoveryEvent::Added(info.clone())),
+                    Some(old) if old != info => Some(DiscoveryEvent::Changed(info.clone())),
+                    Some(_) => None,
+                });
+        let invalid = new
+            .errors
+            .iter()
+            .filter(|(path, _)| !self.errors.contains_key(*path))
+            .map(|(_, error)| DiscoveryEvent::Invalid(error.clone()));
+        removed.chain(added_or_changed).chain(invalid).collect()
+    }
+}
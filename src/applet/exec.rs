@@ -0,0 +1,315 @@
+use std::{env, fmt, io, iter::Peekable, path::PathBuf, str::Chars};
+
+use crate::applet::interface::Direction;
+
+/// An error in the `exec` line of an applet manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    /// The line contains no program.
+    Empty,
+    /// A quoted string is missing its closing quote.
+    Unterminated(char),
+    /// The line ends with an escaping backslash.
+    TrailingBackslash,
+    /// A `%` is followed by something other than a field code.
+    InvalidFieldCode(Option<char>),
+    /// A `${}` names no variable.
+    EmptyVariable,
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::Empty => write!(f, "exec line is empty"),
+            ExecError::Unterminated(quote) => write!(f, "missing closing {quote} in exec line"),
+            ExecError::TrailingBackslash => write!(f, "exec line ends with a backslash"),
+            ExecError::InvalidFieldCode(Some(code)) => {
+                write!(
+                    f,
+                    "invalid field code %{code} in exec line, use %% for a literal %"
+                )
+            }
+            ExecError::InvalidFieldCode(None) => {
+                write!(f, "exec line ends with %, use %% for a literal %")
+            }
+            ExecError::EmptyVariable => write!(f, "empty variable name ${{}} in exec line"),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+/// An error launching an applet.
+#[derive(Debug)]
+pub enum LaunchError {
+    Exec(ExecError),
+    /// The program does not exist or is not in `PATH`.
+    NotFound(String),
+    /// The program is not executable by the current user.
+    PermissionDenied(String),
+    /// The working directory does not exist or is not a directory.
+    WorkingDir(PathBuf),
+    /// No applet definition has the given id.
+    UnknownDefinition(String),
+    /// The applet cannot be shown on a panel facing the given direction.
+    UnsupportedDirection(Direction),
+    Io(io::Error),
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LaunchError::Exec(error) => error.fmt(f),
+            LaunchError::NotFound(program) => write!(f, "{program}: command not found"),
+            LaunchError::PermissionDenied(program) => write!(f, "{program}: permission denied"),
+            LaunchError::WorkingDir(dir) => {
+                write!(f, "{}: working directory does not exist", dir.display())
+            }
+            LaunchError::UnknownDefinition(id) => write!(f, "no applet definition with id {id}"),
+            LaunchError::UnsupportedDirection(direction) => {
+                let direction = match direction {
+                    Direction::Left => "left",
+                    Direction::Right => "right",
+                    Direction::Top => "top",
+                    Direction::Bottom => "bottom",
+                };
+                write!(f, "applet cannot be shown on a {direction} panel")
+            }
+            LaunchError::Io(error) => write!(f, "failed to launch applet: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for LaunchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LaunchError::Exec(error) => Some(error),
+            LaunchError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<ExecError> for LaunchError {
+    fn from(error: ExecError) -> Self {
+        LaunchError::Exec(error)
+    }
+}
+
+impl LaunchError {
+    /// Classifies the error returned when spawning `program`.
+    pub(crate) fn spawn(program: &str, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => LaunchError::NotFound(program.to_string()),
+            io::ErrorKind::PermissionDenied => LaunchError::PermissionDenied(program.to_string()),
+            _ => LaunchError::Io(error),
+        }
+    }
+}
+
+/// Splits an exec line into a program and its arguments, the way desktop
+/// entries are parsed.
+///
+/// Arguments are separated by whitespace and may be quoted with `"` or `'`,
+/// or escaped with `\`. Outside of single quotes `$VAR` and `${VAR}` are
+/// replaced with the value of the environment variable, and an unquoted `~`
+/// at the start of an argument with the home directory. Desktop entry field
+/// codes such as `%f` or `%U` are removed and `%%` stands for `%`.
+pub fn split_exec(exec: &str) -> Result<Vec<String>, ExecError> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = exec.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(ExecError::Unterminated('\'')),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '`' | '$' | '\\')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(ExecError::Unterminated('"')),
+                        },
+                        Some('$') => expand_variable(&mut chars, &mut current)?,
+                        Some(c) => current.push(c),
+                        None => return Err(ExecError::Unterminated('"')),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                current.push(chars.next().ok_or(ExecError::TrailingBackslash)?);
+            }
+            // Like in a shell, an unquoted variable that expands to nothing
+            // does not make an argument on its own.
+            '$' => {
+                let len = current.len();
+                expand_variable(&mut chars, &mut current)?;
+                in_word |= current.len() > len;
+            }
+            '~' if !in_word && chars.peek().is_none_or(|c| *c == '/' || c.is_whitespace()) => {
+                in_word = true;
+                match dirs::home_dir() {
+                    Some(home) => current.push_str(&home.to_string_lossy()),
+                    None => current.push('~'),
+                }
+            }
+            '%' => match chars.next() {
+                Some('%') => {
+                    in_word = true;
+                    current.push('%');
+                }
+                Some(
+                    'f' | 'F' | 'u' | 'U' | 'i' | 'c' | 'k' | 'd' | 'D' | 'n' | 'N' | 'v' | 'm',
+                ) => {}
+                code => return Err(ExecError::InvalidFieldCode(code)),
+            },
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        args.push(current);
+    }
+
+    if args.is_empty() {
+        return Err(ExecError::Empty);
+    }
+    Ok(args)
+}
+
+/// Expands `~` at the start of a path to the home directory.
+pub fn expand_home(path: &std::path::Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Appends the value of the variable named after a `$` to `dst`, or the `$`
+/// itself if no name follows it.
+fn expand_variable(chars: &mut Peekable<Chars<'_>>, dst: &mut String) -> Result<(), ExecError> {
+    let mut name = String::new();
+    if chars.next_if_eq(&'{').is_some() {
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => name.push(c),
+                None => return Err(ExecError::Unterminated('}')),
+            }
+        }
+        if name.is_empty() {
+            return Err(ExecError::EmptyVariable);
+        }
+    } else {
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+    }
+
+    if name.is_empty() {
+        dst.push('$');
+    } else if let Ok(value) = env::var(&name) {
+        dst.push_str(&value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VAR: &str = "AZALEA_EXEC_TEST";
+    const UNSET: &str = "AZALEA_EXEC_TEST_UNSET";
+
+    fn set_vars() {
+        static SET: std::sync::Once = std::sync::Once::new();
+        // SAFETY: no other test reads or writes these variables.
+        SET.call_once(|| unsafe {
+            env::set_var(VAR, "a b");
+            env::remove_var(UNSET);
+        });
+    }
+
+    #[test]
+    fn split() {
+        set_vars();
+        let home = dirs::home_dir().unwrap().to_string_lossy().into_owned();
+        let cases: &[(&str, &[&str])] = &[
+            ("prog", &["prog"]),
+            ("  prog  a\\tb  ", &["prog", "atb"]),
+            ("prog 'a b' \"c d\"", &["prog", "a b", "c d"]),
+            ("prog '' \"\"", &["prog", "", ""]),
+            ("prog a'b'\"c\"", &["prog", "abc"]),
+            ("prog a\\ b", &["prog", "a b"]),
+            ("prog '\\$x'", &["prog", "\\$x"]),
+            ("prog \"\\\"\\$\\`\\\\\\x\"", &["prog", "\"$`\\\\x"]),
+            ("prog $AZALEA_EXEC_TEST", &["prog", "a b"]),
+            ("prog ${AZALEA_EXEC_TEST}x", &["prog", "a bx"]),
+            ("prog \"-$AZALEA_EXEC_TEST-\"", &["prog", "-a b-"]),
+            ("prog '$AZALEA_EXEC_TEST'", &["prog", "$AZALEA_EXEC_TEST"]),
+            ("prog $AZALEA_EXEC_TEST_UNSET end", &["prog", "end"]),
+            ("prog ${AZALEA_EXEC_TEST_UNSET}", &["prog"]),
+            ("prog \"$AZALEA_EXEC_TEST_UNSET\"", &["prog", ""]),
+            ("prog a$AZALEA_EXEC_TEST_UNSET", &["prog", "a"]),
+            ("prog $ $-", &["prog", "$", "$-"]),
+            ("prog a~ '~' \\~", &["prog", "a~", "~", "~"]),
+            ("prog 100%% %f %U -%u", &["prog", "100%", "-"]),
+        ];
+        for (exec, expected) in cases {
+            let expected = expected.iter().map(|arg| arg.to_string()).collect();
+            assert_eq!(split_exec(exec), Ok(expected), "{exec}");
+        }
+        assert_eq!(
+            split_exec("~ ~/bin"),
+            Ok(vec![home.clone(), format!("{home}/bin")])
+        );
+    }
+
+    #[test]
+    fn split_errors() {
+        set_vars();
+        let cases = [
+            ("", ExecError::Empty),
+            ("   ", ExecError::Empty),
+            ("%f %U", ExecError::Empty),
+            ("$AZALEA_EXEC_TEST_UNSET", ExecError::Empty),
+            ("prog 'a", ExecError::Unterminated('\'')),
+            ("prog \"a", ExecError::Unterminated('"')),
+            ("prog \"a\\", ExecError::Unterminated('"')),
+            ("prog ${AZALEA_EXEC_TEST", ExecError::Unterminated('}')),
+            ("prog \"${AZALEA_EXEC_TEST\"", ExecError::Unterminated('}')),
+            ("prog a\\", ExecError::TrailingBackslash),
+            ("prog ${}", ExecError::EmptyVariable),
+            ("prog %x", ExecError::InvalidFieldCode(Some('x'))),
+            ("prog %", ExecError::InvalidFieldCode(None)),
+        ];
+        for (exec, expected) in cases {
+            assert_eq!(split_exec(exec), Err(expected), "{exec}");
+        }
+    }
+}
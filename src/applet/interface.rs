@@ -1,15 +1,24 @@
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
     fs,
     path::{Path, PathBuf},
-    process::{Child, Command},
+    process::{Child, Command, ExitStatus, Stdio},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc::UnboundedSender;
 
-use super::serde_types::{LimitsDef, SizeDef};
+use super::{
+    connection::{APPLET_ENV, ConnectionError, SOCKET_ENV, TOKEN_ENV},
+    exec::{LaunchError, expand_home, split_exec},
+    layout::LayoutError,
+    log::{LogBuffer, LogLine},
+    serde_types::{ColorDef, LimitsDef, SizeDef},
+    settings::{Config, Setting},
+};
 use iced::{
-    Size, Task,
+    Color, Size, Task,
     futures::{self, SinkExt},
     window,
 };
@@ -18,24 +27,225 @@ use iced_layershell::{
     actions::{ActionCallback, IcedNewMenuSettings, IcedNewPopupSettings, IcedXdgWindowSettings},
     reexport::{Anchor, NewLayerShellSettings},
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast::Sender, mpsc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::{broadcast::Sender, mpsc, oneshot};
+
+use crate::{
+    runtime,
+    theme::{Theme, ThemeType},
+};
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum RuntimeRequest {
     Launch {
         info: AppletInfo,
         order: u32,
         position: AppletPosition,
     },
+    /// Asks the applet to quit, terminating it if it does not comply.
+    Stop(u32),
+    /// Stops the applet and launches it again with the same definition.
+    Restart(u32),
+    /// Stops the applet and launches it again with its definition re-read
+    /// from disk.
+    Reload(u32),
+    /// Enables or disables reloading running applets whenever their
+    /// definition changes on disk.
+    ReloadOnChange(bool),
+    /// Enables or disables appending applet output to their
+    /// [`log_path`](super::log::log_path).
+    PersistLogs(bool),
+    /// Places the applet definition with the given id on the panel, saving it
+    /// to the [`PanelLayout`](super::layout::PanelLayout) and launching it.
+    Add {
+        id: String,
+        order: u32,
+        position: AppletPosition,
+    },
+    /// Moves an applet, saving its new place to the layout.
+    Move {
+        id: u32,
+        order: u32,
+        position: AppletPosition,
+    },
+    /// Stops an applet and removes it from the layout.
+    Remove(u32),
+    /// Changes the settings of an applet, saving them to the layout and
+    /// sending the applet its new [`Config`].
+    Configure { id: u32, config: Config },
 }
 
 #[derive(Debug, Clone)]
 pub enum RuntimeMessage {
     Ready(UnboundedSender<RuntimeRequest>),
     New(Applet),
+    /// An applet definition file could not be read or is invalid.
+    InvalidDefinition(DefinitionError),
+    /// The applet definition with the given id could not be launched.
+    LaunchFailed {
+        id: String,
+        error: Arc<LaunchError>,
+    },
+    /// An applet completed the handshake with the given negotiated terms.
+    Connected(u32, Hello),
+    /// A connection was refused, because its first frame is invalid, it could
+    /// not be matched to an applet or its protocol is incompatible. The id is
+    /// the pid of the peer if it was not matched to an applet.
+    Rejected(u32, String),
+    /// An applet's connection failed and was closed.
+    Disconnected(u32, Arc<ConnectionError>),
+    /// The runtime failed to start or to accept a connection.
+    Error(Arc<ConnectionError>),
     Request(AppletRequest, u32),
-    Shutdown(u32),
+    /// An applet process exited and was removed from the runtime.
+    Shutdown(u32, ExitStatus),
+    /// An applet the runtime adopted disconnected or was stopped, and was
+    /// removed from the runtime.
+    Detached(u32),
+    /// An applet wrote a line to its standard output or error.
+    Log(u32, LogLine),
+    /// An applet was moved to a new place on the panel.
+    Moved {
+        id: u32,
+        order: u32,
+        position: AppletPosition,
+    },
+    /// The panel layout could not be read or saved.
+    LayoutError(Arc<LayoutError>),
+    /// An applet changed its [`AppletState`].
+    State(u32, AppletState),
+    /// An applet's settings were changed.
+    Configured(u32, Config),
+    /// An applet was hidden because it cannot be shown on a panel facing the
+    /// given direction.
+    UnsupportedDirection(u32, Direction),
+}
+
+/// The version of the applet protocol spoken by this crate.
+///
+/// Protocol 1 only has the sizing and visibility events and requests,
+/// protocol 2 adds everything else. Events and requests are held back from
+/// peers that negotiated an older protocol than the one introducing them, see
+/// [`AppletEvent::protocol`] and [`AppletRequest::protocol`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest protocol version this crate can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, exchanged as a bit set during the handshake.
+///
+/// None are defined yet, features that come with a protocol version are
+/// gated on the negotiated [`Hello::protocol`] instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+
+    /// The capabilities implemented by this crate.
+    pub const SUPPORTED: Self = Self::NONE;
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// The first frame sent by an applet on every connection.
+///
+/// It is decoded before a version is negotiated, so fields are only ever
+/// appended. JSON peers may leave out the optional ones, while bincode can
+/// neither skip unknown fields nor default missing ones, so the runtime falls
+/// back to the layout of protocol 1, which ends with `token`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol: u32,
+    pub version: String,
+    pub capabilities: Capabilities,
+    /// The secret the runtime passed to the applet through
+    /// [`TOKEN_ENV`](super::connection::TOKEN_ENV), identifying it regardless
+    /// of which process connects.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The id of the applet definition, read from
+    /// [`APPLET_ENV`](super::connection::APPLET_ENV), through which a runtime
+    /// that did not launch the applet can adopt it.
+    #[serde(default)]
+    pub applet: Option<String>,
+}
+
+impl Hello {
+    /// Returns the [`Hello`] describing this crate.
+    pub fn current() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: Capabilities::SUPPORTED,
+            token: None,
+            applet: None,
+        }
+    }
+
+    /// Negotiates the terms of a connection with `peer`, downgrading to the
+    /// older of both protocols and the capabilities both sides share.
+    pub fn negotiate(&self, peer: &Hello) -> Result<Hello, String> {
+        if peer.protocol < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "peer speaks protocol {} (libazalea {}), at least {} is required",
+                peer.protocol, peer.version, MIN_PROTOCOL_VERSION
+            ));
+        }
+        Ok(Hello {
+            protocol: self.protocol.min(peer.protocol),
+            version: self.version.clone(),
+            capabilities: self.capabilities & peer.capabilities,
+            token: None,
+            applet: None,
+        })
+    }
+}
+
+/// The runtime's answer to an applet's [`Hello`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Handshake {
+    Accepted(Hello),
+    Rejected(RejectReason),
+}
+
+/// Why the runtime refused an applet's [`Hello`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// The connection could not be matched to an applet the runtime launched
+    /// or can adopt.
+    UnknownApplet,
+    /// The applet speaks a protocol the runtime cannot talk to, for the given
+    /// reason.
+    Incompatible(String),
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::UnknownApplet => write!(f, "unknown applet"),
+            RejectReason::Incompatible(reason) => write!(f, "{reason}"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,6 +261,123 @@ pub enum AppletEvent {
     Direction(Direction),
     Show,
     Hide,
+    /// The runtime wants the applet to exit.
+    Quit,
+    /// The host's answer to the [`AppletRequest::Call`] with the same id.
+    Reply(u64, Reply),
+    /// A value published on a topic the applet subscribed to.
+    Publication {
+        topic: String,
+        payload: Payload,
+    },
+    /// An output the applet is shown on appeared or its geometry or scale
+    /// changed.
+    OutputAdded(Output),
+    /// The output with the given name disappeared.
+    OutputRemoved(String),
+    /// The fractional scale of the output the applet is shown on, used for
+    /// surfaces not placed on an announced [`Output`].
+    Scale(f64),
+    /// The thickness of the panel and the padding around its applets, in
+    /// logical pixels. The grid size becomes the thickness without the
+    /// padding on both sides.
+    Panel {
+        thickness: u32,
+        padding: u32,
+    },
+    /// The user chose the [`MenuAction`] with the given id.
+    MenuAction(String),
+    /// The host's theme changed, applied unless
+    /// [`Applet::follows_host_theme`](super::Applet::follows_host_theme) is
+    /// overridden.
+    Theme(ThemeSeed),
+    /// The values of the applet's [`AppletInfo::settings`], sent after
+    /// connecting and whenever the user changes them.
+    Config(Config),
+}
+
+impl AppletEvent {
+    /// Returns the oldest protocol version that has this event.
+    pub fn protocol(&self) -> u32 {
+        match self {
+            AppletEvent::GridSize(_)
+            | AppletEvent::Size(..)
+            | AppletEvent::Margin { .. }
+            | AppletEvent::Direction(_)
+            | AppletEvent::Show
+            | AppletEvent::Hide => 1,
+            AppletEvent::Quit
+            | AppletEvent::Reply(..)
+            | AppletEvent::Publication { .. }
+            | AppletEvent::OutputAdded(_)
+            | AppletEvent::OutputRemoved(_)
+            | AppletEvent::Scale(_)
+            | AppletEvent::Panel { .. }
+            | AppletEvent::MenuAction(_)
+            | AppletEvent::Theme(_)
+            | AppletEvent::Config(_) => 2,
+        }
+    }
+}
+
+/// The colors and type the host derives its [`Theme`] from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThemeSeed {
+    #[serde(with = "ColorDef")]
+    pub primary: Color,
+    #[serde(with = "ColorDef")]
+    pub secondary: Color,
+    #[serde(with = "ColorDef")]
+    pub tertiary: Color,
+    pub scheme: ColorScheme,
+}
+
+impl ThemeSeed {
+    /// Derives the colors of the theme from `seed`, and whether it is dark
+    /// from the host's `theme`, resolving [`ThemeType::System`] against the
+    /// host's preferences so that applets do not resolve it against their
+    /// own.
+    pub fn from_seed(seed: Color, theme: &Theme) -> Self {
+        let [primary, secondary, tertiary] = Theme::seed_colors(seed);
+        let scheme = if theme.is_dark_scheme() {
+            ColorScheme::Dark
+        } else {
+            ColorScheme::Light
+        };
+        Self {
+            primary,
+            secondary,
+            tertiary,
+            scheme,
+        }
+    }
+
+    pub fn theme(&self) -> Theme {
+        let theme_type = match self.scheme {
+            ColorScheme::Dark => ThemeType::Dark,
+            ColorScheme::Light => ThemeType::Light,
+        };
+        Theme::from_colors(self.primary, self.secondary, self.tertiary, theme_type)
+    }
+}
+
+/// Whether the theme in a [`ThemeSeed`] is dark or light.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorScheme {
+    Dark,
+    Light,
+}
+
+/// A monitor the panel is shown on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    /// The name of the output, e.g. `DP-1`.
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub scale: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,9 +386,240 @@ pub enum AppletRequest {
     Limits(Limits),
     #[serde(with = "SizeDef")]
     Intrinsic(Size),
+    /// The limits of the layer surface on `output`, which is `None` for the
+    /// surface shown before the host announces any output.
+    OutputLimits {
+        output: Option<String>,
+        #[serde(with = "LimitsDef")]
+        limits: Limits,
+    },
+    /// The intrinsic size of the layer surface on `output`, see
+    /// [`AppletRequest::OutputLimits`].
+    OutputIntrinsic {
+        output: Option<String>,
+        #[serde(with = "SizeDef")]
+        size: Size,
+    },
+    /// Asks the host a question, to be answered with an [`AppletEvent::Reply`]
+    /// carrying the same id.
+    Call(u64, Query),
+    /// Subscribes to a topic, receiving its retained value right away.
+    Subscribe(String),
+    Unsubscribe(String),
+    /// Publishes a value on a topic, retaining it for later subscribers.
+    Publish {
+        topic: String,
+        payload: Payload,
+    },
+    /// Asks the host to show or hide the applet, e.g. while it is idle.
+    SetVisible(bool),
+    Tooltip(Option<String>),
+    /// A count shown over the applet, such as unread notifications.
+    Badge(Option<u32>),
+    /// Asks the host to draw attention to the applet.
+    Urgent(bool),
+    /// Replaces the actions the host shows in the context menu of the applet.
+    Menu(Vec<MenuAction>),
+}
+
+impl AppletRequest {
+    /// Returns the oldest protocol version that has this request.
+    pub fn protocol(&self) -> u32 {
+        match self {
+            AppletRequest::Limits(_) | AppletRequest::Intrinsic(_) => 1,
+            AppletRequest::OutputLimits { .. }
+            | AppletRequest::OutputIntrinsic { .. }
+            | AppletRequest::Call(..)
+            | AppletRequest::Subscribe(_)
+            | AppletRequest::Unsubscribe(_)
+            | AppletRequest::Publish { .. }
+            | AppletRequest::SetVisible(_)
+            | AppletRequest::Tooltip(_)
+            | AppletRequest::Badge(_)
+            | AppletRequest::Urgent(_)
+            | AppletRequest::Menu(_) => 2,
+        }
+    }
+
+    /// Returns this request as a peer speaking `protocol` knows it, falling
+    /// back to [`AppletRequest::Limits`] and [`AppletRequest::Intrinsic`] for
+    /// the sizes of single surfaces, or `None` if it has no such form.
+    pub fn for_protocol(self, protocol: u32) -> Option<Self> {
+        match self {
+            AppletRequest::OutputLimits { limits, .. } if protocol < 2 => {
+                Some(AppletRequest::Limits(limits))
+            }
+            AppletRequest::OutputIntrinsic { size, .. } if protocol < 2 => {
+                Some(AppletRequest::Intrinsic(size))
+            }
+            request => (request.protocol() <= protocol).then_some(request),
+        }
+    }
+}
+
+/// An action an applet contributes to its context menu. Choosing it sends an
+/// [`AppletEvent::MenuAction`] with its id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MenuAction {
+    pub id: String,
+    pub label: String,
+    /// The path of an SVG icon.
+    #[serde(default)]
+    pub icon: Option<PathBuf>,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Whether a checkable action is checked, `None` if it is not checkable.
+    #[serde(default)]
+    pub checked: Option<bool>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl MenuAction {
+    pub fn new(id: impl Into<String>, label: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            label: label.into(),
+            icon: None,
+            enabled: true,
+            checked: None,
+        }
+    }
+}
+
+/// What an applet asked the host to show about it through
+/// [`AppletRequest::SetVisible`], [`AppletRequest::Tooltip`],
+/// [`AppletRequest::Badge`], [`AppletRequest::Urgent`] and
+/// [`AppletRequest::Menu`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppletState {
+    pub visible: bool,
+    pub tooltip: Option<String>,
+    pub badge: Option<u32>,
+    pub urgent: bool,
+    pub menu: Vec<MenuAction>,
+}
+
+impl Default for AppletState {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            tooltip: None,
+            badge: None,
+            urgent: false,
+            menu: Vec::new(),
+        }
+    }
+}
+
+impl AppletState {
+    /// Applies `request` if it changes the state, returning whether it did.
+    pub fn apply(&mut self, request: &AppletRequest) -> bool {
+        let old = self.clone();
+        match request {
+            AppletRequest::SetVisible(visible) => self.visible = *visible,
+            AppletRequest::Tooltip(tooltip) => self.tooltip.clone_from(tooltip),
+            AppletRequest::Badge(badge) => self.badge = *badge,
+            AppletRequest::Urgent(urgent) => self.urgent = *urgent,
+            AppletRequest::Menu(menu) => self.menu.clone_from(menu),
+            _ => {}
+        }
+        *self != old
+    }
+
+    /// Returns the requests that lead from the default state to this one.
+    pub fn requests(&self) -> Vec<AppletRequest> {
+        let default = Self::default();
+        let mut requests = Vec::new();
+        if self.visible != default.visible {
+            requests.push(AppletRequest::SetVisible(self.visible));
+        }
+        if self.tooltip.is_some() {
+            requests.push(AppletRequest::Tooltip(self.tooltip.clone()));
+        }
+        if self.badge.is_some() {
+            requests.push(AppletRequest::Badge(self.badge));
+        }
+        if self.urgent != default.urgent {
+            requests.push(AppletRequest::Urgent(self.urgent));
+        }
+        if !self.menu.is_empty() {
+            requests.push(AppletRequest::Menu(self.menu.clone()));
+        }
+        requests
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// A value published on the applet bus, encoded as JSON so that it can be
+/// carried by any [`WireFormat`](super::connection::WireFormat).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Payload(String);
+
+impl Payload {
+    pub fn new<T: Serialize>(value: &T) -> serde_json::Result<Self> {
+        serde_json::to_string(value).map(Self)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.0)
+    }
+}
+
+/// A question an applet can ask its host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Query {
+    /// The geometry and scale of the output with the given name, as used by
+    /// [`AppletCore::output`].
+    Output(String),
+    /// The thickness of the panel, in logical pixels.
+    PanelThickness,
+    /// Where the applet sits on the panel.
+    Placement,
+    /// Whether the applet may open a popup now. Hosts refuse it e.g. while
+    /// the popup of another applet is open.
+    Popup,
+}
+
+/// The host's answer to a [`Query`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Reply {
+    /// The output asked for, `None` if the host does not know it.
+    Output(Option<Output>),
+    PanelThickness(u32),
+    Placement {
+        position: AppletPosition,
+        order: u32,
+    },
+    /// Whether the popup was granted.
+    Popup(bool),
+    /// The host does not know how to answer the query.
+    Unsupported,
+}
+
+/// An error returned by [`AppletCore::call`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// The host did not reply in time.
+    Timeout,
+    /// The applet is not connected to a runtime.
+    Disconnected,
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::Timeout => write!(f, "the host did not reply in time"),
+            CallError::Disconnected => write!(f, "not connected to a runtime"),
+        }
+    }
+}
+
+impl Error for CallError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AppletPosition {
     Start,
     Center,
@@ -74,12 +632,43 @@ pub struct Applet {
     pub order: u32,
     pub info: AppletInfo,
     pub sender: Sender<AppletEvent>,
+    /// The limits most recently requested for any of the applet's surfaces.
     pub limits: Option<iced::advanced::layout::Limits>,
+    /// The intrinsic size most recently reported for any of the applet's
+    /// surfaces.
     pub intrinsic: Option<Size>,
+    /// The limits and intrinsic size of each of the applet's surfaces, keyed
+    /// by the output it is shown on as in [`AppletRequest::OutputLimits`].
+    pub surfaces: HashMap<Option<String>, SurfaceSize>,
     pub size: Option<Size>,
     pub margin: (i32, i32, i32, i32),
-    pub process: Arc<Mutex<Child>>,
+    /// The applet process, `None` if the runtime adopted an applet it did not
+    /// launch.
+    pub process: Option<Arc<Mutex<Child>>>,
     pub position: AppletPosition,
+    /// How many times the runtime has relaunched this applet in a row.
+    pub restarts: u32,
+    pub launched_at: Instant,
+    pub(crate) token: String,
+    /// The most recent output of the applet process.
+    pub log: LogBuffer,
+    pub state: AppletState,
+    /// The values of the applet's [`AppletInfo::settings`].
+    pub config: Config,
+}
+
+impl Applet {
+    /// Answers the [`AppletRequest::Call`] with the id `call`.
+    pub fn reply(&self, call: u64, reply: Reply) {
+        _ = self.sender.send(AppletEvent::Reply(call, reply));
+    }
+
+    /// Tells the applet that the user chose its [`MenuAction`] `action`.
+    pub fn activate(&self, action: &str) {
+        _ = self
+            .sender
+            .send(AppletEvent::MenuAction(action.to_string()));
+    }
 }
 
 impl PartialEq for Applet {
@@ -89,10 +678,18 @@ impl PartialEq for Applet {
             && self.info == other.info
             && self.limits == other.limits
             && self.intrinsic == other.intrinsic
+            && self.surfaces == other.surfaces
             && self.position == other.position
     }
 }
 
+/// The sizing an applet requested for one of its layer surfaces.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SurfaceSize {
+    pub limits: Option<iced::advanced::layout::Limits>,
+    pub intrinsic: Option<Size>,
+}
+
 impl PartialOrd for Applet {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match self.position.cmp(&other.position) {
@@ -112,53 +709,257 @@ pub fn limits_from_grid(limits: &Limits, size: u32) -> Limits {
     )
 }
 
+/// What an applet does when its connection to the runtime is lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DisconnectPolicy {
+    /// Exit along with the runtime.
+    #[default]
+    Exit,
+    /// Keep running and try to connect again with backoff, also while the
+    /// runtime does not know the applet.
+    ///
+    /// Applets launched by a runtime are terminated when it dies, so this
+    /// only lets them survive a dropped connection. Applets started on their
+    /// own outlive the runtime and are adopted by the next one, which places
+    /// them by the definition named in [`APPLET_ENV`] when they reconnect.
+    ///
+    /// [`APPLET_ENV`]: super::connection::APPLET_ENV
+    Reconnect,
+}
+
 #[derive(Debug, Clone)]
 pub enum AppletMessage {
     Ready(mpsc::UnboundedSender<AppletRequest>),
+    Connected(Hello),
+    Rejected(String),
+    Disconnected(Arc<ConnectionError>),
+    /// The applet will try to connect again after the given delay.
+    Reconnecting(Duration),
     Event(AppletEvent),
 }
 
+/// The state of the connection between an applet and the runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    /// The connection was lost and the applet tries again after the given
+    /// delay.
+    Reconnecting(Duration),
+    /// The connection was lost or refused for good.
+    Disconnected,
+}
+
+/// An applet definition, read from a `.toml` manifest.
+///
+/// `name` and `description` can be localized with `name[de]`-style keys, see
+/// [`AppletInfo::localized_name`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct AppletInfo {
     pub id: String,
     pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
     pub keywords: Vec<String>,
     pub exec: String,
+    /// Environment variables set for the applet process.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// The directory the applet is started in, where a leading `~` stands
+    /// for the home directory.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
     pub icon: PathBuf,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// The oldest protocol version the applet can speak.
+    #[serde(default)]
+    pub min_protocol: Option<u32>,
+    /// The panel directions the applet supports, or all if empty.
+    #[serde(default)]
+    pub directions: Vec<Direction>,
+    #[serde(default)]
+    pub default_position: Option<AppletPosition>,
+    #[serde(default)]
+    pub default_order: Option<u32>,
+    /// The settings the user can change, stored per applet in the
+    /// [`PanelLayout`](super::layout::PanelLayout) and sent as
+    /// [`AppletEvent::Config`].
+    #[serde(default)]
+    pub settings: Vec<Setting>,
+    /// The localized variants of `name` and `description`, keyed by
+    /// `field[locale]`.
+    #[serde(flatten)]
+    pub localized: HashMap<String, String>,
+}
+
+/// The applet definitions found on disk by [`AppletInfo::load`].
+#[derive(Debug, Clone, Default)]
+pub struct Definitions {
+    pub applets: Vec<AppletInfo>,
+    pub errors: Vec<DefinitionError>,
+}
+
+/// A definition file that could not be read or parsed.
+#[derive(Debug, Clone)]
+pub struct DefinitionError {
+    pub path: PathBuf,
+    pub error: Arc<dyn Error + Send + Sync>,
+}
+
+impl std::fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+/// Whether the runtime relaunches an applet after its process exits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Relaunch when the applet exits unsuccessfully, backing off exponentially.
+    OnFailure,
+    /// Relaunch whenever the applet exits, backing off exponentially.
+    Always,
+}
+
+impl RestartPolicy {
+    const BASE_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    /// Returns how long to wait before relaunching an applet that exited with
+    /// `status` after `restarts` consecutive relaunches, or `None` if it should
+    /// stay down.
+    pub fn delay(self, status: ExitStatus, restarts: u32) -> Option<Duration> {
+        match self {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnFailure if status.success() => None,
+            RestartPolicy::OnFailure | RestartPolicy::Always => Some(
+                Self::BASE_DELAY
+                    .saturating_mul(1 << restarts.min(16))
+                    .min(Self::MAX_DELAY),
+            ),
+        }
+    }
 }
 
 impl AppletInfo {
-    pub fn all() -> Result<Vec<Self>, Box<dyn Error>> {
-        let applets_dir = dirs::home_dir()
-            .ok_or("No home directory")?
-            .join(Path::new(".config/azalea/applets"));
-        let dir = fs::read_dir(applets_dir)?;
-        Ok(dir
+    /// Returns the directories applet definitions are read from, most
+    /// preferred first: `$XDG_CONFIG_HOME/azalea/applets`,
+    /// `$XDG_DATA_HOME/azalea/applets` and `azalea/applets` in each of
+    /// `$XDG_DATA_DIRS`.
+    pub fn dirs() -> Vec<PathBuf> {
+        let xdg = xdg::BaseDirectories::with_prefix("azalea");
+        xdg.get_config_home()
             .into_iter()
-            .filter_map(|e| {
-                let entry = e.ok()?;
-                let content = fs::read_to_string(entry.path()).ok()?;
-                toml::from_str(&content).ok()
-            })
-            .collect())
+            .chain(xdg.get_data_home())
+            .chain(xdg.get_data_dirs())
+            .map(|dir| dir.join("applets"))
+            .collect()
     }
 
-    pub fn launch(&self) -> Result<Child, Box<dyn Error>> {
-        let args: Vec<_> = self.exec.split_whitespace().collect();
-        let child = Command::new(args.get(0).ok_or("invalid index")?)
-            .args(args.get(1..).unwrap_or_default())
-            .spawn()?;
-        Ok(child)
+    /// Reads every `.toml` file in [`AppletInfo::dirs`].
+    ///
+    /// When several files define the same id, the one in the most preferred
+    /// directory wins, so that user definitions override system ones.
+    pub fn load() -> Definitions {
+        let mut definitions = Definitions::default();
+        for dir in Self::dirs() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut paths: Vec<_> = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            paths.sort();
+
+            for path in paths {
+                match Self::from_path(&path) {
+                    Ok(info) => {
+                        if !definitions.applets.iter().any(|known| known.id == info.id) {
+                            definitions.applets.push(info);
+                        }
+                    }
+                    Err(error) => definitions.errors.push(DefinitionError {
+                        path,
+                        error: error.into(),
+                    }),
+                }
+            }
+        }
+        definitions
     }
 
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+    /// Spawns the applet, pointing it at the runtime listening on `socket`
+    /// and handing it the `token` it identifies itself with.
+    ///
+    /// The exec line is parsed with [`split_exec`]. The standard output and
+    /// error of the applet are piped and must be read by the caller. On Linux
+    /// the applet is sent `SIGTERM` when the thread that launched it exits, so
+    /// this must be called from a thread living as long as the runtime.
+    pub fn launch(&self, socket: &Path, token: &str) -> Result<Child, LaunchError> {
+        let args = split_exec(&self.exec)?;
+        let mut command = Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .envs(&self.env)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .env(SOCKET_ENV, socket)
+            .env(TOKEN_ENV, token)
+            .env(APPLET_ENV, &self.id);
+        if let Some(dir) = &self.working_dir {
+            let dir = expand_home(dir);
+            if !dir.is_dir() {
+                return Err(LaunchError::WorkingDir(dir));
+            }
+            command.current_dir(dir);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let parent = std::process::id() as libc::pid_t;
+            // SAFETY: prctl, getppid and raise are async-signal-safe.
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    // The runtime may have died before the signal was set up.
+                    if libc::getppid() != parent {
+                        libc::raise(libc::SIGTERM);
+                    }
+                    Ok(())
+                });
+            }
+        }
+        command
+            .spawn()
+            .map_err(|error| LaunchError::spawn(&args[0], error))
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let content = fs::read_to_string(path)?;
         let toml: Self = toml::from_str(&content)?;
+        toml.validate()?;
         Ok(toml)
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Left,
     Right,
@@ -201,11 +1002,26 @@ pub struct AppletCore {
     pub(crate) sender: Option<futures::channel::mpsc::UnboundedSender<AppletCoreRequest>>,
     pub(crate) applet_sender: Option<UnboundedSender<AppletRequest>>,
     pub(crate) pending_requests: Vec<AppletCoreRequest>,
-    pub(crate) applet_id: Option<iced::window::Id>,
-    pub(crate) intrinsic: Option<Size<f32>>,
-    pub(crate) limits: Option<Limits>,
+    /// The layer surfaces of the applet and the outputs they are shown on,
+    /// `None` until the host announces any output.
+    pub(crate) surfaces: HashMap<iced::window::Id, Option<String>>,
+    pub(crate) outputs: Vec<Output>,
+    pub(crate) theme: Option<Theme>,
+    pub(crate) config: Config,
+    /// The intrinsic sizes and limits of the layer surfaces last reported to
+    /// the runtime.
+    pub(crate) intrinsic: HashMap<iced::window::Id, Size<f32>>,
+    pub(crate) limits: HashMap<iced::window::Id, Limits>,
+    pub(crate) protocol: Option<Hello>,
+    pub(crate) connection: ConnectionState,
+    pub(crate) calls: Arc<Mutex<HashMap<u64, oneshot::Sender<Reply>>>>,
+    pub(crate) next_call: u64,
+    pub(crate) topics: HashSet<String>,
     pub margin: Margin,
     pub grid_size: u32,
+    pub scale: f64,
+    pub thickness: u32,
+    pub padding: u32,
     pub size: Size<u32>,
     pub direction: Direction,
     pub visible: bool,
@@ -217,18 +1033,184 @@ impl AppletCore {
         Self {
             sender: None,
             applet_sender: None,
-            applet_id: None,
-            intrinsic: None,
-            limits: None,
+            surfaces: HashMap::new(),
+            outputs: Vec::new(),
+            theme: None,
+            config: Config::new(),
+            intrinsic: HashMap::new(),
+            limits: HashMap::new(),
+            protocol: None,
+            connection: ConnectionState::default(),
+            calls: Arc::default(),
+            next_call: 0,
+            topics: HashSet::new(),
             pending_requests: Vec::new(),
             margin: Margin::default(),
             grid_size: 50,
+            scale: 1.0,
+            thickness: 50,
+            padding: 0,
             size: Size::new(50, 50),
             direction: Direction::Bottom,
             layer: iced_layershell::reexport::Layer::Top,
             visible: false,
         }
     }
+
+    /// Returns the terms negotiated with the runtime, once connected.
+    pub fn protocol(&self) -> Option<&Hello> {
+        self.protocol.as_ref()
+    }
+
+    pub fn connection(&self) -> ConnectionState {
+        self.connection
+    }
+
+    /// Returns the scale of the layer surface `window`, which is that of its
+    /// output if the host announced any.
+    pub fn scale(&self, window: iced::window::Id) -> f64 {
+        self.output(window)
+            .map_or(self.scale, |output| output.scale)
+    }
+
+    /// Returns the theme the host sent, if any.
+    pub fn host_theme(&self) -> Option<&Theme> {
+        self.theme.as_ref()
+    }
+
+    /// Returns the values of the applet's settings, empty until the host
+    /// sends them.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the outputs the host announced.
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    /// Returns the name of the output the layer surface `window` was created
+    /// for, even before the host describes it.
+    pub(crate) fn output_name(&self, window: iced::window::Id) -> Option<String> {
+        self.surfaces.get(&window).cloned().flatten()
+    }
+
+    /// Returns the output the layer surface `window` is shown on.
+    pub fn output(&self, window: iced::window::Id) -> Option<&Output> {
+        let name = self.surfaces.get(&window)?.as_ref()?;
+        self.outputs.iter().find(|output| &output.name == name)
+    }
+
+    /// Asks the host a question, failing after [`CALL_TIMEOUT`].
+    pub fn call(&mut self, query: Query) -> Task<Result<Reply, CallError>> {
+        self.call_with_timeout(query, CALL_TIMEOUT)
+    }
+
+    /// Asks the host a question, failing if it does not reply within `timeout`.
+    pub fn call_with_timeout(
+        &mut self,
+        query: Query,
+        timeout: Duration,
+    ) -> Task<Result<Reply, CallError>> {
+        let Some(sender) = &self.applet_sender else {
+            return Task::done(Err(CallError::Disconnected));
+        };
+        let id = self.next_call;
+        self.next_call = self.next_call.wrapping_add(1);
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.calls.lock().unwrap().insert(id, reply_sender);
+        if sender.send(AppletRequest::Call(id, query)).is_err() {
+            self.calls.lock().unwrap().remove(&id);
+            return Task::done(Err(CallError::Disconnected));
+        }
+
+        let calls = self.calls.clone();
+        Task::future(async move {
+            match runtime()
+                .spawn(tokio::time::timeout(timeout, reply_receiver))
+                .await
+            {
+                Ok(Ok(Ok(reply))) => Ok(reply),
+                Ok(Err(_)) => {
+                    calls.lock().unwrap().remove(&id);
+                    Err(CallError::Timeout)
+                }
+                _ => Err(CallError::Disconnected),
+            }
+        })
+    }
+
+    /// Subscribes to `topic`, delivering its values to
+    /// [`Applet::publication`](super::Applet::publication).
+    pub fn subscribe(&mut self, topic: impl Into<String>) {
+        let topic = topic.into();
+        if let Some(sender) = &self.applet_sender {
+            _ = sender.send(AppletRequest::Subscribe(topic.clone()));
+        }
+        self.topics.insert(topic);
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if let Some(sender) = &self.applet_sender {
+            _ = sender.send(AppletRequest::Unsubscribe(topic.to_string()));
+        }
+        self.topics.remove(topic);
+    }
+
+    /// Publishes `value` on `topic` for the other applets subscribed to it.
+    pub fn publish<T: Serialize>(
+        &self,
+        topic: impl Into<String>,
+        value: &T,
+    ) -> serde_json::Result<()> {
+        let payload = Payload::new(value)?;
+        if let Some(sender) = &self.applet_sender {
+            _ = sender.send(AppletRequest::Publish {
+                topic: topic.into(),
+                payload,
+            });
+        }
+        Ok(())
+    }
+
+    /// Asks the host to show or hide the applet.
+    pub fn set_visible(&self, visible: bool) {
+        self.request(AppletRequest::SetVisible(visible));
+    }
+
+    pub fn set_tooltip(&self, tooltip: Option<String>) {
+        self.request(AppletRequest::Tooltip(tooltip));
+    }
+
+    pub fn set_badge(&self, badge: Option<u32>) {
+        self.request(AppletRequest::Badge(badge));
+    }
+
+    /// Asks the host to draw attention to the applet, or to stop doing so.
+    pub fn set_urgent(&self, urgent: bool) {
+        self.request(AppletRequest::Urgent(urgent));
+    }
+
+    /// Sets the actions of the context menu, delivered to
+    /// [`Applet::menu_action`](super::Applet::menu_action) when chosen.
+    pub fn set_menu(&self, menu: Vec<MenuAction>) {
+        self.request(AppletRequest::Menu(menu));
+    }
+
+    fn request(&self, request: AppletRequest) {
+        if let Some(sender) = &self.applet_sender {
+            _ = sender.send(request);
+        }
+    }
+
+    /// Resolves the pending call with the id `call`.
+    pub(crate) fn resolve(&mut self, call: u64, reply: Reply) {
+        if let Some(sender) = self.calls.lock().unwrap().remove(&call) {
+            _ = sender.send(reply);
+        }
+    }
+
     pub fn new_layershell(
         &mut self,
         id: window::Id,
@@ -417,6 +1399,9 @@ impl AppletCore {
     }
 }
 
+/// How long [`AppletCore::call`] waits for the host to reply.
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Default)]
 pub struct Margin {
     pub top: i32,
@@ -472,7 +1457,7 @@ pub enum AppletCoreRequest {
     Close {
         id: window::Id,
     },
-    SendLimits(Limits)
+    SendLimits(window::Id, Limits),
 }
 
 #[derive(Debug, Clone)]
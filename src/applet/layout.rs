@@ -0,0 +1,203 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::applet::interface::AppletPosition;
+
+/// Returns the layout file of the runtime named `instance`, which is
+/// `$XDG_CONFIG_HOME/azalea/layouts/<instance>.toml`.
+pub fn layout_path(instance: &str) -> io::Result<PathBuf> {
+    xdg::BaseDirectories::with_prefix("azalea")
+        .place_config_file(format!("layouts/{instance}.toml"))
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Io(error) => write!(f, "I/O error: {error}"),
+            LayoutError::Parse(error) => write!(f, "invalid layout: {error}"),
+            LayoutError::Serialize(error) => write!(f, "failed to serialize layout: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LayoutError::Io(error) => Some(error),
+            LayoutError::Parse(error) => Some(error),
+            LayoutError::Serialize(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for LayoutError {
+    fn from(error: io::Error) -> Self {
+        LayoutError::Io(error)
+    }
+}
+
+/// The applets placed on a panel, launched by the runtime at startup.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PanelLayout {
+    #[serde(default)]
+    pub applets: Vec<LayoutEntry>,
+}
+
+/// An applet placed on a panel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutEntry {
+    /// The id of the applet definition.
+    pub id: String,
+    pub position: AppletPosition,
+    pub order: u32,
+    /// Settings of this particular applet instance.
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub settings: toml::Table,
+}
+
+impl PanelLayout {
+    /// Reads the layout at `path`, which is empty if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, LayoutError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).map_err(LayoutError::Parse),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Writes the layout to `path`, replacing the file atomically so that it
+    /// is never left half written.
+    pub fn save(&self, path: &Path) -> Result<(), LayoutError> {
+        let content = toml::to_string(self).map_err(LayoutError::Serialize)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let mut file = File::create(&temp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Returns the index of the entry for the applet `id` placed at
+    /// `position` and `order`.
+    pub fn find(&self, id: &str, position: &AppletPosition, order: u32) -> Option<usize> {
+        self.applets
+            .iter()
+            .position(|entry| entry.id == id && &entry.position == position && entry.order == order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an empty directory for the test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("azalea-layout-{}-{name}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(id: &str, position: AppletPosition, order: u32) -> LayoutEntry {
+        LayoutEntry {
+            id: id.to_string(),
+            position,
+            order,
+            settings: toml::Table::new(),
+        }
+    }
+
+    #[test]
+    fn load() {
+        let dir = test_dir("load");
+        let path = dir.join("panel.toml");
+        assert_eq!(PanelLayout::load(&path).unwrap(), PanelLayout::default());
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(PanelLayout::load(&path).unwrap(), PanelLayout::default());
+
+        fs::write(
+            &path,
+            "[[applets]]\nid = \"clock\"\nposition = \"end\"\norder = 2\n\
+             settings = { format = \"%H:%M\" }\n",
+        )
+        .unwrap();
+        let layout = PanelLayout::load(&path).unwrap();
+        assert_eq!(layout.applets.len(), 1);
+        assert_eq!(layout.applets[0].id, "clock");
+        assert_eq!(layout.applets[0].position, AppletPosition::End);
+        assert_eq!(layout.applets[0].order, 2);
+        assert_eq!(layout.applets[0].settings["format"].as_str(), Some("%H:%M"));
+
+        fs::write(&path, "[[applets]]\nid = \"clock\"\n").unwrap();
+        assert!(matches!(
+            PanelLayout::load(&path),
+            Err(LayoutError::Parse(_))
+        ));
+        assert!(matches!(PanelLayout::load(&dir), Err(LayoutError::Io(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save() {
+        let dir = test_dir("save");
+        let path = dir.join("panel.toml");
+        let mut layout = PanelLayout {
+            applets: vec![
+                entry("clock", AppletPosition::End, 0),
+                entry("menu", AppletPosition::Start, 0),
+            ],
+        };
+        layout.applets[0]
+            .settings
+            .insert("format".to_string(), "%H:%M".into());
+
+        layout.save(&path).unwrap();
+        assert_eq!(PanelLayout::load(&path).unwrap(), layout);
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches("settings").count(), 1, "{content}");
+
+        layout.applets.remove(0);
+        layout.save(&path).unwrap();
+        assert_eq!(PanelLayout::load(&path).unwrap(), layout);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(matches!(
+            layout.save(&dir.join("missing/panel.toml")),
+            Err(LayoutError::Io(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn find() {
+        let layout = PanelLayout {
+            applets: vec![
+                entry("clock", AppletPosition::End, 0),
+                entry("clock", AppletPosition::End, 1),
+                entry("clock", AppletPosition::Start, 1),
+            ],
+        };
+        assert_eq!(layout.find("clock", &AppletPosition::End, 0), Some(0));
+        assert_eq!(layout.find("clock", &AppletPosition::End, 1), Some(1));
+        assert_eq!(layout.find("clock", &AppletPosition::Start, 1), Some(2));
+        assert_eq!(layout.find("clock", &AppletPosition::Start, 0), None);
+        assert_eq!(layout.find("menu", &AppletPosition::End, 0), None);
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    sync::mpsc::{UnboundedSender, unbounded_channel},
+};
+
+use crate::{applet::interface::RuntimeMessage, runtime};
+
+/// How many lines of output are kept in memory for each applet.
+pub const LOG_CAPACITY: usize = 1000;
+
+/// The size at which a persisted log file is rotated, in bytes.
+const LOG_FILE_LIMIT: u64 = 1024 * 1024;
+
+/// How many rotated log files are kept besides the current one.
+const LOG_FILE_ROTATIONS: usize = 3;
+
+/// The longest line captured from an applet, in bytes. Longer lines are split.
+const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line written by an applet to its standard output or error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub stream: LogStream,
+    pub time: SystemTime,
+    pub line: String,
+}
+
+/// The most recent output of an applet, holding at most [`LOG_CAPACITY`]
+/// lines.
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<VecDeque<LogLine>>>);
+
+impl LogBuffer {
+    pub fn push(&self, line: LogLine) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == LOG_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns the buffered lines, oldest first.
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the last `count` buffered lines, oldest first.
+    pub fn tail(&self, count: usize) -> Vec<LogLine> {
+        let lines = self.0.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Where the output captured from applets goes besides their [`LogBuffer`].
+#[derive(Debug, Clone)]
+pub(crate) struct LogSink {
+    pub messages: UnboundedSender<RuntimeMessage>,
+    /// Whether output is also appended to [`log_path`].
+    pub persist: Arc<AtomicBool>,
+}
+
+/// Returns the file the output of the applet `id` is persisted to, which is
+/// `$XDG_STATE_HOME/azalea/logs/<id>.log`.
+pub fn log_path(id: &str) -> io::Result<PathBuf> {
+    xdg::BaseDirectories::with_prefix("azalea").place_state_file(format!("logs/{id}.log"))
+}
+
+/// A persisted log file, created on the first write and rotated once it grows
+/// past [`LOG_FILE_LIMIT`].
+#[derive(Debug)]
+pub(crate) struct LogFile {
+    id: String,
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+impl LogFile {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            path: None,
+            file: None,
+        }
+    }
+
+    /// Spawns a task appending the lines sent to it to the file, on a
+    /// blocking thread so that slow disks do not hold up the runtime.
+    pub fn spawn_writer(mut self) -> UnboundedSender<LogLine> {
+        let (sender, mut receiver) = unbounded_channel::<LogLine>();
+        runtime().spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                let mut lines = vec![line];
+                while let Ok(line) = receiver.try_recv() {
+                    lines.push(line);
+                }
+                let write = move || {
+                    for line in &lines {
+                        _ = self.write(line);
+                    }
+                    self
+                };
+                match tokio::task::spawn_blocking(write).await {
+                    Ok(file) => self = file,
+                    Err(_) => return,
+                }
+            }
+        });
+        sender
+    }
+
+    fn write(&mut self, line: &LogLine) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => self.path.insert(log_path(&self.id)?).clone(),
+        };
+        let size = match &self.file {
+            Some(file) => file.metadata()?.len(),
+            None => fs::metadata(&path).map_or(0, |metadata| metadata.len()),
+        };
+        if size >= LOG_FILE_LIMIT {
+            self.rotate(&path)?;
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(OpenOptions::new().create(true).append(true).open(&path)?),
+        };
+        let stream = match line.stream {
+            LogStream::Stdout => "out",
+            LogStream::Stderr => "err",
+        };
+        let time = line
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            file,
+            "{}.{:03} {stream} {}",
+            time.as_secs(),
+            time.subsec_millis(),
+            line.line
+        )
+    }
+
+    fn rotate(&mut self, path: &Path) -> io::Result<()> {
+        self.file = None;
+        let rotated = |index: usize| {
+            let mut path = path.to_path_buf().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+        for index in (1..LOG_FILE_ROTATIONS).rev() {
+            if rotated(index).exists() {
+                fs::rename(rotated(index), rotated(index + 1))?;
+            }
+        }
+        fs::rename(path, rotated(1))
+    }
+}
+
+/// Reads the lines an applet writes to `stream` into its buffer, forwarding
+/// them as [`RuntimeMessage::Log`] and to the writer of its log file when
+/// persisting.
+///
+/// Lines longer than [`MAX_LINE_LENGTH`] are split.
+pub(crate) async fn capture(
+    id: u32,
+    stream: impl AsyncRead + Unpin,
+    kind: LogStream,
+    buffer: LogBuffer,
+    file: UnboundedSender<LogLine>,
+    sink: LogSink,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut bytes = Vec::new();
+    loop {
+        bytes.clear();
+        let mut line = (&mut reader).take(MAX_LINE_LENGTH as u64);
+        match line.read_until(b'\n', &mut bytes).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let line = LogLine {
+            stream: kind,
+            time: SystemTime::now(),
+            line: String::from_utf8_lossy(&bytes).trim_end().to_string(),
+        };
+
+        if sink.persist.load(Ordering::Relaxed) {
+            _ = file.send(line.clone());
+        }
+        buffer.push(line.clone());
+        _ = sink.messages.send(RuntimeMessage::Log(id, line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> LogLine {
+        LogLine {
+            stream: LogStream::Stdout,
+            time: SystemTime::UNIX_EPOCH,
+            line: text.to_string(),
+        }
+    }
+
+    fn texts(lines: Vec<LogLine>) -> Vec<String> {
+        lines.into_iter().map(|line| line.line).collect()
+    }
+
+    #[test]
+    fn buffer() {
+        let buffer = LogBuffer::default();
+        assert!(buffer.lines().is_empty());
+        for index in 0..3 {
+            buffer.push(line(&index.to_string()));
+        }
+        assert_eq!(texts(buffer.lines()), ["0", "1", "2"]);
+        assert_eq!(texts(buffer.tail(2)), ["1", "2"]);
+        assert_eq!(texts(buffer.tail(5)), ["0", "1", "2"]);
+
+        for index in 3..LOG_CAPACITY + 5 {
+            buffer.push(line(&index.to_string()));
+        }
+        let lines = texts(buffer.lines());
+        assert_eq!(lines.len(), LOG_CAPACITY);
+        assert_eq!(lines[0], "5");
+        assert_eq!(lines[LOG_CAPACITY - 1], (LOG_CAPACITY + 4).to_string());
+        assert_eq!(texts(buffer.tail(1)), [(LOG_CAPACITY + 4).to_string()]);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("azalea-log-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("clock.log");
+        let mut file = LogFile {
+            id: "clock".to_string(),
+            path: Some(path.clone()),
+            file: None,
+        };
+
+        // Each line takes a quarter of the limit, so that every fifth line
+        // starts a new file.
+        let text = "x".repeat(LOG_FILE_LIMIT as usize / 4);
+        for _ in 0..4 {
+            file.write(&line(&text)).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() >= LOG_FILE_LIMIT);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        file.write(&line("first")).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "0.000 out first\n");
+        assert!(dir.join("clock.log.1").exists());
+
+        for rotations in 2..LOG_FILE_ROTATIONS + 3 {
+            for _ in 0..4 {
+                file.write(&line(&text)).unwrap();
+            }
+            file.write(&line(&rotations.to_string())).unwrap();
+        }
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["clock.log", "clock.log.1", "clock.log.2", "clock.log.3"]
+        );
+        let first = fs::read_to_string(dir.join("clock.log.1")).unwrap();
+        assert!(first.starts_with(&format!("0.000 out {}\n", LOG_FILE_ROTATIONS + 1)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_split() {
+        let (messages, mut received) = unbounded_channel();
+        let sink = LogSink {
+            messages,
+            persist: Arc::default(),
+        };
+        let long = "y".repeat(MAX_LINE_LENGTH + 10);
+        let input = format!("short\n{long}\nend");
+        let buffer = LogBuffer::default();
+        let (file, _) = unbounded_channel();
+        capture(
+            1,
+            input.as_bytes(),
+            LogStream::Stderr,
+            buffer.clone(),
+            file,
+            sink,
+        )
+        .await;
+
+        let lines = buffer.lines();
+        assert!(lines.iter().all(|line| line.stream == LogStream::Stderr));
+        assert_eq!(
+            texts(lines),
+            [
+                "short".to_string(),
+                "y".repeat(MAX_LINE_LENGTH),
+                "y".repeat(10),
+                "end".to_string()
+            ]
+        );
+        let mut count = 0;
+        while let Ok(message) = received.try_recv() {
+            assert!(matches!(message, RuntimeMessage::Log(1, _)));
+            count += 1;
+        }
+        assert_eq!(count, 4);
+    }
+}
@@ -0,0 +1,355 @@
+use std::{env, fmt};
+
+use crate::applet::{
+    exec::{ExecError, split_exec},
+    interface::{AppletInfo, Direction, PROTOCOL_VERSION},
+    settings::{Setting, SettingKind},
+};
+
+/// The fields of a manifest that can be localized.
+const LOCALIZABLE: [&str; 2] = ["name", "description"];
+
+/// A problem with the contents of an applet manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    /// A required field is empty.
+    Empty(&'static str),
+    /// The id contains characters other than ASCII letters, digits, `.`, `-`
+    /// and `_`.
+    InvalidId(String),
+    /// The applet requires a newer protocol than this crate speaks.
+    UnsupportedProtocol(u32),
+    /// The manifest contains a key that is neither a known field nor a
+    /// localized variant of one.
+    UnknownKey(String),
+    /// The setting with the given key is declared twice or can never have a
+    /// valid value.
+    InvalidSetting(String),
+    Exec(ExecError),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Empty(field) => write!(f, "`{field}` must not be empty"),
+            ManifestError::InvalidId(id) => write!(
+                f,
+                "invalid id `{id}`, only ASCII letters, digits, `.`, `-` and `_` are allowed"
+            ),
+            ManifestError::UnsupportedProtocol(version) => write!(
+                f,
+                "applet requires protocol {version}, but only {PROTOCOL_VERSION} is supported"
+            ),
+            ManifestError::UnknownKey(key) => write!(f, "unknown key `{key}`"),
+            ManifestError::InvalidSetting(key) => write!(f, "invalid setting `{key}`"),
+            ManifestError::Exec(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl AppletInfo {
+    /// Checks that the manifest is complete and usable by this crate.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        if self.id.is_empty() {
+            return Err(ManifestError::Empty("id"));
+        }
+        if !self
+            .id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            return Err(ManifestError::InvalidId(self.id.clone()));
+        }
+        if self.name.is_empty() {
+            return Err(ManifestError::Empty("name"));
+        }
+        if self.exec.trim().is_empty() {
+            return Err(ManifestError::Empty("exec"));
+        }
+        split_exec(&self.exec).map_err(ManifestError::Exec)?;
+        if let Some(version) = self.min_protocol.filter(|v| *v > PROTOCOL_VERSION) {
+            return Err(ManifestError::UnsupportedProtocol(version));
+        }
+        if let Some(key) = self.localized.keys().find(|key| !is_localized_key(key)) {
+            return Err(ManifestError::UnknownKey(key.clone()));
+        }
+        for (index, setting) in self.settings.iter().enumerate() {
+            if setting.key.is_empty() {
+                return Err(ManifestError::Empty("settings.key"));
+            }
+            if !is_valid_setting(setting)
+                || self.settings[..index].iter().any(|s| s.key == setting.key)
+            {
+                return Err(ManifestError::InvalidSetting(setting.key.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the name in the current locale.
+    pub fn localized_name(&self) -> &str {
+        self.localized("name").unwrap_or(&self.name)
+    }
+
+    /// Returns the description in the current locale.
+    pub fn localized_description(&self) -> &str {
+        self.localized("description").unwrap_or(&self.description)
+    }
+
+    /// Returns whether the applet can be shown on a panel facing `direction`.
+    pub fn supports(&self, direction: Direction) -> bool {
+        self.directions.is_empty() || self.directions.contains(&direction)
+    }
+
+    fn localized(&self, field: &str) -> Option<&str> {
+        let locale = current_locale()?;
+        locale_variants(&locale).find_map(|variant| {
+            self.localized
+                .get(&format!("{field}[{variant}]"))
+                .map(String::as_str)
+        })
+    }
+}
+
+fn is_valid_setting(setting: &Setting) -> bool {
+    match &setting.kind {
+        SettingKind::Int { min, max, default } => (min..=max).contains(&default),
+        SettingKind::Color { default } => iced::Color::parse(default).is_some(),
+        _ => setting.validate(setting.default_value()).is_some(),
+    }
+}
+
+fn is_localized_key(key: &str) -> bool {
+    let Some((field, rest)) = key.split_once('[') else {
+        return false;
+    };
+    LOCALIZABLE.contains(&field) && rest.len() > 1 && rest.ends_with(']')
+}
+
+/// Returns the locale used for messages, following the precedence of
+/// `LC_ALL`, `LC_MESSAGES` and `LANG`.
+fn current_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .filter(|locale| locale != "C" && locale != "POSIX")
+}
+
+/// Returns the variants of a `lang_COUNTRY.ENCODING@MODIFIER` locale to look
+/// up, most specific first, as described by the desktop entry specification.
+fn locale_variants(locale: &str) -> impl Iterator<Item = String> {
+    let (locale, modifier) = match locale.split_once('@') {
+        Some((locale, modifier)) => (locale, Some(modifier)),
+        None => (locale, None),
+    };
+    let locale = locale.split('.').next().unwrap_or(locale);
+    let (lang, country) = match locale.split_once('_') {
+        Some((lang, country)) => (lang, Some(country)),
+        None => (locale, None),
+    };
+
+    let mut variants = Vec::new();
+    if let (Some(country), Some(modifier)) = (country, modifier) {
+        variants.push(format!("{lang}_{country}@{modifier}"));
+    }
+    if let Some(country) = country {
+        variants.push(format!("{lang}_{country}"));
+    }
+    if let Some(modifier) = modifier {
+        variants.push(format!("{lang}@{modifier}"));
+    }
+    variants.push(lang.to_string());
+    variants.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(extra: &str) -> AppletInfo {
+        toml::from_str(&format!(
+            "id = \"clock\"\nname = \"Clock\"\nexec = \"clock\"\nicon = \"clock.svg\"\n{extra}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(info("").validate(), Ok(()));
+        assert_eq!(
+            info("directions = [\"top\"]\nsettings = [{ key = \"a\", label = \"A\", type = \"bool\" }]")
+                .validate(),
+            Ok(())
+        );
+
+        let cases = [
+            (
+                AppletInfo {
+                    name: String::new(),
+                    ..info("")
+                },
+                ManifestError::Empty("name"),
+            ),
+            (
+                AppletInfo {
+                    exec: " ".to_string(),
+                    ..info("")
+                },
+                ManifestError::Empty("exec"),
+            ),
+            (
+                AppletInfo {
+                    exec: "clock 'a".to_string(),
+                    ..info("")
+                },
+                ManifestError::Exec(ExecError::Unterminated('\'')),
+            ),
+            (
+                info(&format!("min_protocol = {}", PROTOCOL_VERSION + 1)),
+                ManifestError::UnsupportedProtocol(PROTOCOL_VERSION + 1),
+            ),
+            (
+                info("nme = \"Clock\""),
+                ManifestError::UnknownKey("nme".to_string()),
+            ),
+            (
+                info("\"name[]\" = \"Clock\""),
+                ManifestError::UnknownKey("name[]".to_string()),
+            ),
+            (
+                info("\"exec[de]\" = \"uhr\""),
+                ManifestError::UnknownKey("exec[de]".to_string()),
+            ),
+            (
+                info("settings = [{ key = \"\", label = \"A\", type = \"bool\" }]"),
+                ManifestError::Empty("settings.key"),
+            ),
+            (
+                info(
+                    "settings = [{ key = \"a\", label = \"A\", type = \"bool\" }, \
+                     { key = \"a\", label = \"B\", type = \"bool\" }]",
+                ),
+                ManifestError::InvalidSetting("a".to_string()),
+            ),
+            (
+                info(
+                    "settings = [{ key = \"a\", label = \"A\", type = \"int\", min = 1, max = 9, default = 0 }]",
+                ),
+                ManifestError::InvalidSetting("a".to_string()),
+            ),
+            (
+                info(
+                    "settings = [{ key = \"a\", label = \"A\", type = \"color\", default = \"red\" }]",
+                ),
+                ManifestError::InvalidSetting("a".to_string()),
+            ),
+        ];
+        for (info, expected) in cases {
+            assert_eq!(info.validate(), Err(expected), "{info:?}");
+        }
+    }
+
+    #[test]
+    fn id_charset() {
+        for id in ["clock", "org.example.Clock", "my-clock_2"] {
+            let info = AppletInfo {
+                id: id.to_string(),
+                ..info("")
+            };
+            assert_eq!(info.validate(), Ok(()), "{id}");
+        }
+        assert_eq!(
+            AppletInfo {
+                id: String::new(),
+                ..info("")
+            }
+            .validate(),
+            Err(ManifestError::Empty("id"))
+        );
+        for id in ["my clock", "clock/2", "uhr✓", "a:b"] {
+            let info = AppletInfo {
+                id: id.to_string(),
+                ..info("")
+            };
+            assert_eq!(
+                info.validate(),
+                Err(ManifestError::InvalidId(id.to_string())),
+                "{id}"
+            );
+        }
+    }
+
+    #[test]
+    fn supports() {
+        assert!(info("").supports(Direction::Left));
+        let info = info("directions = [\"top\", \"bottom\"]");
+        assert!(info.supports(Direction::Top));
+        assert!(info.supports(Direction::Bottom));
+        assert!(!info.supports(Direction::Left));
+    }
+
+    #[test]
+    fn variants() {
+        let cases: &[(&str, &[&str])] = &[
+            ("de", &["de"]),
+            ("de_AT", &["de_AT", "de"]),
+            ("de_AT.UTF-8", &["de_AT", "de"]),
+            ("sr@latin", &["sr@latin", "sr"]),
+            (
+                "sr_RS.UTF-8@latin",
+                &["sr_RS@latin", "sr_RS", "sr@latin", "sr"],
+            ),
+        ];
+        for (locale, expected) in cases {
+            assert_eq!(locale_variants(locale).collect::<Vec<_>>(), *expected);
+        }
+    }
+
+    /// Everything that reads the locale from the environment is tested here,
+    /// so that no other test observes the variables changing.
+    #[test]
+    fn locale() {
+        let set = |all: &str, messages: &str, lang: &str| {
+            // SAFETY: no other test reads or writes these variables.
+            unsafe {
+                env::set_var("LC_ALL", all);
+                env::set_var("LC_MESSAGES", messages);
+                env::set_var("LANG", lang);
+            }
+        };
+
+        set("", "", "de_DE.UTF-8");
+        assert_eq!(current_locale().as_deref(), Some("de_DE.UTF-8"));
+        set("", "fr_FR", "de_DE");
+        assert_eq!(current_locale().as_deref(), Some("fr_FR"));
+        set("it_IT", "fr_FR", "de_DE");
+        assert_eq!(current_locale().as_deref(), Some("it_IT"));
+        set("C", "fr_FR", "de_DE");
+        assert_eq!(current_locale(), None);
+        set("", "", "POSIX");
+        assert_eq!(current_locale(), None);
+
+        let info = info(
+            "\"name[de]\" = \"Uhr\"\n\"name[de_AT]\" = \"Zeit\"\n\
+             \"name[sr@latin]\" = \"Sat\"\n\"description[de]\" = \"Zeigt die Uhrzeit\"",
+        );
+        for (locale, name) in [
+            ("de_AT.UTF-8", "Zeit"),
+            ("de_CH.UTF-8", "Uhr"),
+            ("sr_RS@latin", "Sat"),
+            ("sr_RS", "Clock"),
+            ("en_US", "Clock"),
+            ("C", "Clock"),
+        ] {
+            set("", "", locale);
+            assert_eq!(info.localized_name(), name, "{locale}");
+        }
+        set("", "", "de_AT");
+        assert_eq!(info.localized_description(), "Zeigt die Uhrzeit");
+        set("", "", "en_US");
+        assert_eq!(info.localized_description(), "");
+    }
+}
@@ -0,0 +1,78 @@
+use iced::{
+    Element, Length,
+    widget::{Column, Text, svg},
+};
+
+use crate::{
+    applet::interface::{Applet, AppletPosition, MenuAction},
+    theme::Theme,
+    widget::menu::{MenuItem, Section},
+};
+
+/// An entry chosen from the context menu of an applet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuChoice {
+    /// An action contributed by the applet, to be passed to
+    /// [`Applet::activate`].
+    Applet(String),
+    Move(AppletPosition),
+    Settings,
+    Remove,
+}
+
+/// Builds the context menu of `applet`, listing the actions it contributed
+/// above the ones the host offers for every applet.
+pub fn context_menu<'a, Message: Clone + 'a>(
+    applet: &'a Applet,
+    on_choice: impl Fn(MenuChoice) -> Message + 'a,
+) -> Element<'a, Message, Theme> {
+    let actions = Section::with_children(applet.state.menu.iter().map(|action| {
+        action_item(action).on_press_maybe(
+            action
+                .enabled
+                .then(|| on_choice(MenuChoice::Applet(action.id.clone()))),
+        )
+    }))
+    .title(applet.info.localized_name().to_string());
+
+    let host = Section::with_children(
+        [
+            (AppletPosition::Start, "Move to start"),
+            (AppletPosition::Center, "Move to center"),
+            (AppletPosition::End, "Move to end"),
+        ]
+        .into_iter()
+        .filter(|(position, _)| *position != applet.position)
+        .map(|(position, label)| {
+            MenuItem::new(Text::new(label).into()).on_press(on_choice(MenuChoice::Move(position)))
+        }),
+    )
+    .push_maybe((!applet.info.settings.is_empty()).then(|| {
+        MenuItem::new(Text::new("Settings").into()).on_press(on_choice(MenuChoice::Settings))
+    }))
+    .push(
+        MenuItem::new(Text::new("Remove from panel").into())
+            .on_press(on_choice(MenuChoice::Remove)),
+    );
+
+    let mut column = Column::new();
+    if !applet.state.menu.is_empty() {
+        column = column.push(actions);
+    }
+    column.push(host).into()
+}
+
+fn action_item<'a, Message: Clone + 'a>(action: &'a MenuAction) -> MenuItem<'a, Message> {
+    let mut item = MenuItem::new(Text::new(&action.label).into());
+    if let Some(icon) = &action.icon {
+        item = item.leading(svg(icon).width(16).height(16).into());
+    }
+    if let Some(checked) = action.checked {
+        item = item.trailing(
+            Text::new(if checked { "✓" } else { "" })
+                .width(Length::Shrink)
+                .into(),
+        );
+    }
+    item
+}
@@ -1,19 +1,32 @@
+mod bus;
 pub mod connection;
+pub mod control;
+pub mod discovery;
+pub mod exec;
 pub mod interface;
+pub mod layout;
+pub mod log;
+pub mod manifest;
+pub mod menu;
 pub mod serde_types;
+pub mod settings;
 
 use iced::{
     Element, Executor, Length, Size, Subscription, Task,
     futures::{SinkExt, Stream, StreamExt, channel::mpsc::unbounded},
 };
 use iced_core::layout::Limits;
-use iced_layershell::reexport::NewLayerShellSettings;
+use iced_layershell::reexport::{NewLayerShellSettings, OutputOption};
 use iced_winit::program::{Message, Renderer};
 
 use crate::{
     applet::{
         connection::applet_sub,
-        interface::{AppletCore, AppletCoreRequest, AppletMessage, Margin, limits_from_grid},
+        interface::{
+            AppletCore, AppletCoreRequest, AppletMessage, ConnectionState, DisconnectPolicy,
+            Margin, Output, Payload, limits_from_grid,
+        },
+        settings::Config,
     },
     runtime,
     theme::Theme,
@@ -45,9 +58,12 @@ pub trait Applet: Sized {
 
     fn update(&mut self, message: Self::Message) -> Task<Self::Message>;
 
+    /// Returns the view of the layer surface `window`, shown on `output` if
+    /// the host announced any.
     fn applet_view(
         &self,
         window: iced::window::Id,
+        output: Option<&Output>,
     ) -> (Limits, Element<'_, Self::Message, Theme, Self::Renderer>);
 
     fn window_view(
@@ -101,6 +117,41 @@ pub trait Applet: Sized {
     fn scale_factor(&self, _window: iced::window::Id) -> f64 {
         1.0
     }
+
+    /// Maps a value published on a topic the applet subscribed to through
+    /// [`AppletCore::subscribe`] to a message.
+    fn publication(&self, _topic: &str, _payload: &Payload) -> Option<Self::Message> {
+        None
+    }
+
+    /// Maps the id of a chosen action set through [`AppletCore::set_menu`] to
+    /// a message.
+    fn menu_action(&self, _action: &str) -> Option<Self::Message> {
+        None
+    }
+
+    /// Maps the values of the settings declared in the manifest to a message,
+    /// called after connecting and whenever the user changes them.
+    fn config_changed(&self, _config: &Config) -> Option<Self::Message> {
+        None
+    }
+
+    /// Returns whether [`Applet::theme`] is replaced by the theme the host
+    /// sends, so that the applet matches the panel.
+    fn follows_host_theme(&self) -> bool {
+        true
+    }
+
+    /// Maps a change of the connection to the runtime to a message, e.g. to
+    /// show that the applet is degraded while reconnecting.
+    fn connection_changed(&self, _state: ConnectionState) -> Option<Self::Message> {
+        None
+    }
+
+    /// Returns what the applet does when the runtime goes away.
+    fn disconnect_policy() -> DisconnectPolicy {
+        DisconnectPolicy::Exit
+    }
 }
 
 #[iced_layershell::to_layer_message(multi)]
@@ -111,7 +162,7 @@ enum AzaleaAppMessage<M: Sync + Send + 'static> {
     AppletUpdate(M),
     AppletMessage(AppletMessage),
     CoreRequest(AppletCoreRequest),
-    AppletLayout(Size<f32>),
+    AppletLayout(iced::window::Id, Size<f32>),
 }
 
 struct Instance<A: Applet> {
@@ -123,6 +174,65 @@ where
     A: Applet,
     A::Message: 'static,
 {
+    /// Opens a layer surface on every output that lacks one and closes the
+    /// surfaces of outputs that are gone, or of everything while hidden.
+    fn sync_surfaces(&mut self) -> Task<AzaleaAppMessage<A::Message>> {
+        let core = self.core_mut();
+        let wanted: Vec<Option<String>> = if !core.visible {
+            Vec::new()
+        } else if core.outputs.is_empty() {
+            vec![None]
+        } else {
+            core.outputs.iter().map(|o| Some(o.name.clone())).collect()
+        };
+
+        let mut tasks = Vec::new();
+        core.surfaces.retain(|&id, output| {
+            let keep = wanted.contains(output);
+            if !keep {
+                core.limits.remove(&id);
+                core.intrinsic.remove(&id);
+                tasks.push(Task::done(AzaleaAppMessage::RemoveWindow(id)));
+            }
+            keep
+        });
+        for output in wanted {
+            if core.surfaces.values().any(|o| *o == output) {
+                continue;
+            }
+            let id = iced::window::Id::unique();
+            tasks.push(Task::done(AzaleaAppMessage::NewLayerShell {
+                settings: NewLayerShellSettings {
+                    size: Some((core.size.width, core.size.height)),
+                    layer: core.layer,
+                    anchor: core.direction.to_applet_anchor(),
+                    exclusive_zone: Some(-1),
+                    margin: Some((
+                        core.margin.top,
+                        core.margin.right,
+                        core.margin.bottom,
+                        core.margin.left,
+                    )),
+                    output_option: match &output {
+                        Some(name) => OutputOption::OutputName(name.clone()),
+                        None => OutputOption::None,
+                    },
+                    ..Default::default()
+                },
+                id,
+            }));
+            core.surfaces.insert(id, output);
+        }
+        Task::batch(tasks)
+    }
+
+    fn set_connection(&mut self, state: ConnectionState) -> Task<AzaleaAppMessage<A::Message>> {
+        self.core_mut().connection = state;
+        match self.program.connection_changed(state) {
+            Some(message) => Task::done(AzaleaAppMessage::AppletUpdate(message)),
+            None => Task::none(),
+        }
+    }
 }
 
 impl<A> Applet for Instance<A>
@@ -148,6 +258,10 @@ where
         A::name()
     }
 
+    fn disconnect_policy() -> DisconnectPolicy {
+        A::disconnect_policy()
+    }
+
     /// Creates a new [`Instance`] of the given [`Program`].
     fn new() -> (Self, Task<AzaleaAppMessage<A::Message>>) {
         let (program, task) = A::new();
@@ -171,14 +285,14 @@ where
                 .update(message)
                 .map(AzaleaAppMessage::AppletUpdate),
             AzaleaAppMessage::CoreRequest(request) => match request {
-                AppletCoreRequest::SendLimits(limits) => {
+                AppletCoreRequest::SendLimits(window, limits) => {
                     let core = self.core_mut();
-                    core.limits = Some(limits);
+                    core.limits.insert(window, limits);
+                    let output = core.output_name(window);
                     if let Some(sender) = core.applet_sender.clone() {
                         Task::future(async move {
-                            sender
-                                .send(interface::AppletRequest::Limits(limits))
-                                .unwrap();
+                            _ = sender
+                                .send(interface::AppletRequest::OutputLimits { output, limits });
                         })
                         .map(|_| Self::Message::Ignore)
                     } else {
@@ -238,9 +352,32 @@ where
             },
             AzaleaAppMessage::AppletMessage(message) => match message {
                 AppletMessage::Ready(applet_sender) => {
-                    self.core_mut().applet_sender = Some(applet_sender);
+                    let core = self.core_mut();
+                    for topic in &core.topics {
+                        _ = applet_sender.send(interface::AppletRequest::Subscribe(topic.clone()));
+                    }
+                    core.applet_sender = Some(applet_sender);
                     Task::none()
                 }
+                AppletMessage::Connected(hello) => {
+                    self.core_mut().protocol = Some(hello);
+                    self.set_connection(ConnectionState::Connected)
+                }
+                message @ (AppletMessage::Rejected(_) | AppletMessage::Disconnected(_)) => {
+                    let core = self.core_mut();
+                    let connected = core.protocol.take().is_some();
+                    core.calls.lock().unwrap().clear();
+                    // A rejected applet is never let in, however long it waits.
+                    let rejected = matches!(message, AppletMessage::Rejected(_));
+                    if (connected || rejected) && A::disconnect_policy() == DisconnectPolicy::Exit {
+                        iced::exit()
+                    } else {
+                        self.set_connection(ConnectionState::Disconnected)
+                    }
+                }
+                AppletMessage::Reconnecting(delay) => {
+                    self.set_connection(ConnectionState::Reconnecting(delay))
+                }
                 AppletMessage::Event(applet_event) => match applet_event {
                     interface::AppletEvent::GridSize(size) => {
                         self.core_mut().grid_size = size;
@@ -249,15 +386,14 @@ where
                     interface::AppletEvent::Size(width, height) => {
                         let core = self.core_mut();
                         core.size = Size::new(width, height);
-                        if let Some(id) = core.applet_id {
+                        let anchor = core.direction.to_applet_anchor();
+                        Task::batch(core.surfaces.keys().map(|&id| {
                             Task::done(AzaleaAppMessage::AnchorSizeChange {
                                 id,
-                                anchor: core.direction.to_applet_anchor(),
+                                anchor,
                                 size: (width, height),
                             })
-                        } else {
-                            Task::none()
-                        }
+                        }))
                     }
                     interface::AppletEvent::Margin {
                         top,
@@ -272,75 +408,110 @@ where
                             bottom,
                             left,
                         };
-                        if let Some(id) = core.applet_id {
+                        Task::batch(core.surfaces.keys().map(|&id| {
                             Task::done(AzaleaAppMessage::MarginChange {
                                 id,
                                 margin: (top, right, bottom, left),
                             })
-                        } else {
-                            Task::none()
-                        }
+                        }))
                     }
                     interface::AppletEvent::Direction(direction) => {
                         let core = self.core_mut();
                         core.direction = direction;
-                        if let Some(id) = core.applet_id {
-                            Task::done(AzaleaAppMessage::AnchorSizeChange {
-                                id,
-                                anchor: core.direction.to_applet_anchor(),
-                                size: (core.size.width, core.size.height),
-                            })
-                        } else {
-                            Task::none()
-                        }
+                        let anchor = core.direction.to_applet_anchor();
+                        let size = (core.size.width, core.size.height);
+                        Task::batch(core.surfaces.keys().map(|&id| {
+                            Task::done(AzaleaAppMessage::AnchorSizeChange { id, anchor, size })
+                        }))
                     }
                     interface::AppletEvent::Show => {
-                        let core = self.core_mut();
-                        core.visible = true;
-                        if core.applet_id.is_none() {
-                            let id = iced::window::Id::unique();
-                            core.applet_id = Some(id);
-                            Task::done(AzaleaAppMessage::NewLayerShell {
-                                settings: NewLayerShellSettings {
-                                    size: Some((core.size.width, core.size.height)),
-                                    layer: core.layer,
-                                    anchor: core.direction.to_applet_anchor(),
-                                    exclusive_zone: Some(-1),
-                                    margin: Some((
-                                        core.margin.top,
-                                        core.margin.right,
-                                        core.margin.bottom,
-                                        core.margin.left,
-                                    )),
-                                    ..Default::default()
-                                },
-                                id,
-                            })
-                        } else {
-                            Task::none()
-                        }
+                        self.core_mut().visible = true;
+                        self.sync_surfaces()
                     }
                     interface::AppletEvent::Hide => {
                         let core = self.core_mut();
                         core.visible = false;
-                        if let Some(id) = core.applet_id.take() {
-                            Task::done(AzaleaAppMessage::RemoveWindow(id))
-                        } else {
-                            Task::none()
+                        Task::batch(
+                            core.surfaces
+                                .drain()
+                                .map(|(id, _)| Task::done(AzaleaAppMessage::RemoveWindow(id))),
+                        )
+                    }
+                    interface::AppletEvent::Quit => {
+                        let core = self.core_mut();
+                        core.visible = false;
+                        Task::batch(
+                            core.surfaces
+                                .drain()
+                                .map(|(id, _)| Task::done(AzaleaAppMessage::RemoveWindow(id))),
+                        )
+                        .chain(iced::exit())
+                    }
+                    interface::AppletEvent::OutputAdded(output) => {
+                        let core = self.core_mut();
+                        core.outputs.retain(|o| o.name != output.name);
+                        core.outputs.push(output);
+                        self.sync_surfaces()
+                    }
+                    interface::AppletEvent::OutputRemoved(name) => {
+                        self.core_mut().outputs.retain(|o| o.name != name);
+                        self.sync_surfaces()
+                    }
+                    // Forgetting the intrinsic size makes the next layout, which
+                    // recomputes the limits from the grid, report it again.
+                    interface::AppletEvent::Scale(scale) => {
+                        let core = self.core_mut();
+                        core.scale = scale;
+                        core.intrinsic.clear();
+                        Task::none()
+                    }
+                    interface::AppletEvent::Panel { thickness, padding } => {
+                        let core = self.core_mut();
+                        core.thickness = thickness;
+                        core.padding = padding;
+                        core.grid_size = thickness.saturating_sub(padding.saturating_mul(2)).max(1);
+                        core.intrinsic.clear();
+                        Task::none()
+                    }
+                    interface::AppletEvent::MenuAction(action) => {
+                        match self.program.menu_action(&action) {
+                            Some(message) => Task::done(AzaleaAppMessage::AppletUpdate(message)),
+                            None => Task::none(),
+                        }
+                    }
+                    interface::AppletEvent::Theme(seed) => {
+                        self.core_mut().theme = Some(seed.theme());
+                        Task::none()
+                    }
+                    interface::AppletEvent::Config(config) => {
+                        let message = self.program.config_changed(&config);
+                        self.core_mut().config = config;
+                        match message {
+                            Some(message) => Task::done(AzaleaAppMessage::AppletUpdate(message)),
+                            None => Task::none(),
+                        }
+                    }
+                    interface::AppletEvent::Reply(call, reply) => {
+                        self.core_mut().resolve(call, reply);
+                        Task::none()
+                    }
+                    interface::AppletEvent::Publication { topic, payload } => {
+                        match self.program.publication(&topic, &payload) {
+                            Some(message) => Task::done(AzaleaAppMessage::AppletUpdate(message)),
+                            None => Task::none(),
                         }
                     }
                 },
             },
-            AzaleaAppMessage::AppletLayout(size) => {
+            AzaleaAppMessage::AppletLayout(window, size) => {
                 let core = self.core_mut();
-                if Some(size) != core.intrinsic {
+                if core.intrinsic.get(&window) != Some(&size) {
+                    let output = core.output_name(window);
                     if let Some(sender) = &mut core.applet_sender {
-                    sender
-                        .send(interface::AppletRequest::Intrinsic(size))
-                        .unwrap()
-                }
+                        _ = sender.send(interface::AppletRequest::OutputIntrinsic { output, size });
+                    }
                 }
-                core.intrinsic = Some(size);
+                core.intrinsic.insert(window, size);
                 Task::none()
             }
             _ => Task::none(),
@@ -351,25 +522,26 @@ where
     fn applet_view(
         &self,
         window: iced::window::Id,
+        output: Option<&Output>,
     ) -> (Limits, Element<'_, Self::Message, Theme, A::Renderer>) {
-        let (limits, view) = self.program.applet_view(window);
-        if Some(limits) != self.core().limits {
+        let (limits, view) = self.program.applet_view(window, output);
+        if self.core().limits.get(&window) != Some(&limits) {
             if let Some(mut sender) = self.core().sender.clone() {
                 runtime().spawn(async move {
                     sender
-                        .send(AppletCoreRequest::SendLimits(limits))
+                        .send(AppletCoreRequest::SendLimits(window, limits))
                         .await
                         .unwrap()
                 });
             }
         }
-        let intrinsic = self.core().intrinsic.clone();
+        let intrinsic = self.core().intrinsic.get(&window).copied();
         let sized_box = SizeBox::new(view.map(Self::Message::AppletUpdate))
             .limits(limits_from_grid(&limits, self.core().grid_size))
             .on_layout(move |rect| {
                 let size = rect.size();
                 if Some(size) != intrinsic {
-                    Some(AzaleaAppMessage::AppletLayout(size))
+                    Some(AzaleaAppMessage::AppletLayout(window, size))
                 } else {
                     None
                 }
@@ -395,13 +567,17 @@ where
             Subscription::run(Theme::subscribe).map(|_| Self::Message::ThemeUpdated),
             self.program.subscription().map(Self::Message::AppletUpdate),
             Subscription::run(core_requests_subscribe).map(Self::Message::CoreRequest),
-            Subscription::run(applet_sub).map(Self::Message::AppletMessage),
+            Subscription::run_with(A::disconnect_policy(), |policy| applet_sub(*policy))
+                .map(Self::Message::AppletMessage),
         ])
     }
 
     /// Returns the current theme of the [`Instance`].
     fn theme(&self, window: iced::window::Id) -> Theme {
-        self.program.theme(window)
+        match &self.core().theme {
+            Some(theme) if self.program.follows_host_theme() => theme.clone(),
+            _ => self.program.theme(window),
+        }
     }
 
     /// Returns the current [`theme::Style`] of the [`Instance`].
@@ -409,9 +585,10 @@ where
         self.program.style(theme)
     }
 
-    /// Returns the current scale factor of the [`Instance`].
+    /// Returns the current scale factor of the [`Instance`], combining the
+    /// scale of the output with the one of the program.
     fn scale_factor(&self, window: iced::window::Id) -> f64 {
-        self.program.scale_factor(window)
+        self.core().scale(window) * self.program.scale_factor(window)
     }
 }
 
@@ -424,13 +601,14 @@ where
         &self,
         id: iced::window::Id,
     ) -> Element<AzaleaAppMessage<A::Message>, Theme, A::Renderer> {
-        if Some(id) == self.core().applet_id {
-            let (limits, view) = self.applet_view(id);
-            if Some(limits) != self.core().limits {
+        if self.core().surfaces.contains_key(&id) {
+            let (limits, view) = self.applet_view(id, self.core().output(id));
+            if self.core().limits.get(&id) != Some(&limits) {
                 if let Some(sender) = self.core().applet_sender.clone() {
-                    sender
-                        .send(interface::AppletRequest::Limits(limits))
-                        .unwrap()
+                    _ = sender.send(interface::AppletRequest::OutputLimits {
+                        output: self.core().output_name(id),
+                        limits,
+                    });
                 };
             }
             view
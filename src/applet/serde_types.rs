@@ -1,4 +1,4 @@
-use iced::{Size, advanced::layout::Limits};
+use iced::{Color, Size, advanced::layout::Limits};
 use serde::{Deserialize, Serialize};
 
 /// A set of size constraints for layouting.
@@ -22,9 +22,91 @@ impl From<LimitsDef> for Limits {
 /// An amount of space in 2 dimensions.
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Size")]
-pub(crate) struct SizeDef<T = f32> {
+pub(crate) struct SizeDef {
     /// The width.
-    pub width: T,
+    #[serde(with = "unbounded")]
+    pub width: f32,
     /// The height.
-    pub height: T,
+    #[serde(with = "unbounded")]
+    pub height: f32,
+}
+
+/// A color in the sRGB color space.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Color")]
+pub(crate) struct ColorDef {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+/// Writes infinite lengths as `null` in human-readable formats, which cannot
+/// represent them otherwise.
+mod unbounded {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.is_finite().then_some(*value).serialize(serializer)
+        } else {
+            value.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        if deserializer.is_human_readable() {
+            Ok(Option::<f32>::deserialize(deserializer)?.unwrap_or(f32::INFINITY))
+        } else {
+            f32::deserialize(deserializer)
+        }
+    }
+}
+
+/// Serializes an optional [`Limits`] through [`LimitsDef`].
+pub(crate) mod option_limits {
+    use iced::advanced::layout::Limits;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::LimitsDef;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(#[serde(with = "LimitsDef")] Limits);
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Limits>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.map(Wrapper).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Limits>, D::Error> {
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(limits)| limits))
+    }
+}
+
+/// Serializes an optional [`Size`] through [`SizeDef`].
+pub(crate) mod option_size {
+    use iced::Size;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::SizeDef;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(#[serde(with = "SizeDef")] Size);
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Size>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.map(Wrapper).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Size>, D::Error> {
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(size)| size))
+    }
 }
@@ -0,0 +1,296 @@
+use std::collections::{BTreeMap, HashMap};
+
+use iced::{
+    Color, Element, Length,
+    widget::{Button, Row, Text, TextInput, Toggler},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    applet::serde_types::ColorDef,
+    theme::{Theme, button, text_input, toggler},
+    widget::{boxed_list::BoxedList, list_item::ListItem},
+};
+
+/// A setting an applet declares in its manifest, e.g.
+///
+/// ```toml
+/// [[settings]]
+/// key = "interval"
+/// label = "Refresh interval"
+/// type = "int"
+/// min = 1
+/// max = 60
+/// default = 5
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Setting {
+    pub key: String,
+    pub label: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(flatten)]
+    pub kind: SettingKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SettingKind {
+    Bool {
+        #[serde(default)]
+        default: bool,
+    },
+    Int {
+        min: i64,
+        max: i64,
+        default: i64,
+    },
+    /// One of `options`, defaulting to the first.
+    Enum {
+        options: Vec<String>,
+        #[serde(default)]
+        default: Option<String>,
+    },
+    String {
+        #[serde(default)]
+        default: String,
+    },
+    /// A color written as `#rrggbb` or `#rrggbbaa`.
+    Color {
+        default: String,
+    },
+}
+
+/// The value of a [`Setting`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SettingValue {
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Color(#[serde(with = "ColorDef")] Color),
+}
+
+impl SettingValue {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            SettingValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            SettingValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            SettingValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_color(&self) -> Option<Color> {
+        match self {
+            SettingValue::Color(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn to_toml(&self) -> toml::Value {
+        match self {
+            SettingValue::Bool(value) => toml::Value::Boolean(*value),
+            SettingValue::Int(value) => toml::Value::Integer(*value),
+            SettingValue::String(value) => toml::Value::String(value.clone()),
+            SettingValue::Color(value) => toml::Value::String(color_to_hex(*value)),
+        }
+    }
+}
+
+/// The values of all settings of an applet, keyed by [`Setting::key`].
+pub type Config = BTreeMap<String, SettingValue>;
+
+impl Setting {
+    /// Returns the value used when none or an invalid one is stored.
+    pub fn default_value(&self) -> SettingValue {
+        match &self.kind {
+            SettingKind::Bool { default } => SettingValue::Bool(*default),
+            SettingKind::Int { min, max, default } => {
+                SettingValue::Int((*default).clamp(*min, (*max).max(*min)))
+            }
+            SettingKind::Enum { options, default } => SettingValue::String(
+                default
+                    .clone()
+                    .or_else(|| options.first().cloned())
+                    .unwrap_or_default(),
+            ),
+            SettingKind::String { default } => SettingValue::String(default.clone()),
+            SettingKind::Color { default } => {
+                SettingValue::Color(Color::parse(default).unwrap_or(Color::BLACK))
+            }
+        }
+    }
+
+    /// Returns `value` if it is valid for this setting.
+    pub fn validate(&self, value: SettingValue) -> Option<SettingValue> {
+        match (&self.kind, value) {
+            (SettingKind::Bool { .. }, value @ SettingValue::Bool(_)) => Some(value),
+            (SettingKind::Int { min, max, .. }, SettingValue::Int(value)) => (*min..=*max)
+                .contains(&value)
+                .then_some(SettingValue::Int(value)),
+            (SettingKind::Enum { options, .. }, SettingValue::String(value)) => options
+                .contains(&value)
+                .then_some(SettingValue::String(value)),
+            (SettingKind::String { .. }, value @ SettingValue::String(_)) => Some(value),
+            (SettingKind::Color { .. }, value @ SettingValue::Color(_)) => Some(value),
+            (SettingKind::Color { .. }, SettingValue::String(value)) => {
+                Color::parse(&value).map(SettingValue::Color)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses text typed for this setting, returning the value if it is
+    /// valid.
+    pub fn parse(&self, input: &str) -> Option<SettingValue> {
+        let value = match &self.kind {
+            SettingKind::Bool { .. } => SettingValue::Bool(input.parse().ok()?),
+            SettingKind::Int { .. } => SettingValue::Int(input.trim().parse().ok()?),
+            _ => SettingValue::String(input.to_string()),
+        };
+        self.validate(value)
+    }
+
+    fn read_toml(&self, value: &toml::Value) -> Option<SettingValue> {
+        let value = match value {
+            toml::Value::Boolean(value) => SettingValue::Bool(*value),
+            toml::Value::Integer(value) => SettingValue::Int(*value),
+            toml::Value::String(value) => SettingValue::String(value.clone()),
+            _ => return None,
+        };
+        self.validate(value)
+    }
+}
+
+/// Returns the value of every setting in `schema`, read from the `stored`
+/// values of a [`LayoutEntry`](super::layout::LayoutEntry).
+pub fn config(schema: &[Setting], stored: &toml::Table) -> Config {
+    schema
+        .iter()
+        .map(|setting| {
+            let value = stored
+                .get(&setting.key)
+                .and_then(|value| setting.read_toml(value))
+                .unwrap_or_else(|| setting.default_value());
+            (setting.key.clone(), value)
+        })
+        .collect()
+}
+
+/// Converts `config` back to the values stored in the layout.
+pub fn to_table(config: &Config) -> toml::Table {
+    config
+        .iter()
+        .map(|(key, value)| (key.clone(), value.to_toml()))
+        .collect()
+}
+
+fn color_to_hex(color: Color) -> String {
+    let [r, g, b, a] = color.into_rgba8();
+    if a == u8::MAX {
+        format!("#{r:02x}{g:02x}{b:02x}")
+    } else {
+        format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+    }
+}
+
+/// Builds a settings page for `schema`, showing the values in `config`.
+///
+/// Text typed into a field is passed to `on_draft` and shown from `drafts`
+/// until it is submitted, when `on_change` receives the value if it is valid.
+/// Callers should keep the drafts in their state and remove the draft of a
+/// setting once it changes.
+pub fn settings_page<'a, Message: Clone + 'a>(
+    schema: &'a [Setting],
+    config: &'a Config,
+    drafts: &'a HashMap<String, String>,
+    on_draft: impl Fn(String, String) -> Message + Clone + 'a,
+    on_change: impl Fn(String, SettingValue) -> Message + Clone + 'a,
+) -> Element<'a, Message, Theme> {
+    BoxedList::with_children(schema.iter().map(|setting| {
+        let value = config
+            .get(&setting.key)
+            .cloned()
+            .unwrap_or_else(|| setting.default_value());
+        let control = setting_control(
+            setting,
+            value,
+            drafts.get(&setting.key),
+            on_draft.clone(),
+            on_change.clone(),
+        );
+        let mut item = ListItem::new(Text::new(&setting.label))
+            .trailing(control)
+            .padding(8);
+        if !setting.description.is_empty() {
+            item = item.subtitle(Text::new(&setting.description));
+        }
+        item.into()
+    }))
+    .width(Length::Fill)
+    .into()
+}
+
+fn setting_control<'a, Message: Clone + 'a>(
+    setting: &'a Setting,
+    value: SettingValue,
+    draft: Option<&String>,
+    on_draft: impl Fn(String, String) -> Message + 'a,
+    on_change: impl Fn(String, SettingValue) -> Message + 'a,
+) -> Element<'a, Message, Theme> {
+    let key = setting.key.clone();
+    match &setting.kind {
+        SettingKind::Bool { .. } => Toggler::new(value.as_bool().unwrap_or_default())
+            .on_toggle(move |value| on_change(key.clone(), SettingValue::Bool(value)))
+            .style(toggler::regular)
+            .into(),
+        SettingKind::Enum { options, .. } => Row::with_children(options.iter().map(|option| {
+            let style = if value.as_str() == Some(option.as_str()) {
+                button::suggested
+            } else {
+                button::regular
+            };
+            Button::new(Text::new(option))
+                .on_press(on_change(key.clone(), SettingValue::String(option.clone())))
+                .style(style)
+                .into()
+        }))
+        .spacing(4)
+        .into(),
+        SettingKind::Int { .. } | SettingKind::String { .. } | SettingKind::Color { .. } => {
+            let text = match (draft, &value) {
+                (Some(draft), _) => draft.clone(),
+                (None, SettingValue::Int(value)) => value.to_string(),
+                (None, SettingValue::String(value)) => value.clone(),
+                (None, SettingValue::Color(value)) => color_to_hex(*value),
+                (None, SettingValue::Bool(value)) => value.to_string(),
+            };
+            // Only a valid draft can be submitted, an invalid one is kept
+            // for the user to correct.
+            let parsed = draft.map(|draft| setting.parse(draft));
+            let style = match parsed {
+                Some(None) => text_input::danger,
+                _ => text_input::regular,
+            };
+            let submit = parsed.flatten().map(|value| on_change(key.clone(), value));
+            TextInput::new(&setting.label, &text)
+                .on_input(move |input| on_draft(key.clone(), input))
+                .on_submit_maybe(submit)
+                .style(style)
+                .width(200)
+                .into()
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::{env, process::ExitCode};
+
+use libazalea::applet::{
+    connection::DEFAULT_INSTANCE,
+    control::{AppletStatus, ControlClient, ControlRequest, ControlResponse},
+    interface::{AppletEvent, AppletPosition, Direction},
+    log::LogStream,
+};
+
+const USAGE: &str = "\
+usage: azalea-ctl [--instance <name>] <command>
+
+commands:
+    list                             list the applets launched by the runtime
+    launch <id> [start|center|end]   launch the applet definition <id>
+    stop <pid>                       stop an applet
+    restart <pid>                    restart an applet
+    show <pid>                       send a Show event
+    hide <pid>                       send a Hide event
+    size <pid> <width> <height>      send a Size event
+    direction <pid> <left|right|top|bottom>
+                                     send a Direction event
+    limits <pid>                     print the limits an applet requested
+    log <pid>                        print the recent output of an applet";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut instance = DEFAULT_INSTANCE.to_string();
+    if args.first().is_some_and(|arg| arg == "--instance") {
+        if args.len() < 2 {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+        instance = args.remove(1);
+        args.remove(0);
+    }
+
+    let request = match parse(&args) {
+        Ok(request) => request,
+        Err(error) => {
+            eprintln!("azalea-ctl: {error}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let response = match ControlClient::connect(&instance).await {
+        Ok(mut client) => client.request(&request).await,
+        Err(error) => Err(error),
+    };
+
+    match response {
+        Ok(ControlResponse::Ok) => ExitCode::SUCCESS,
+        Ok(ControlResponse::Applets(applets)) => {
+            print_applets(&applets);
+            ExitCode::SUCCESS
+        }
+        Ok(ControlResponse::Limits(Some(limits))) => {
+            let (min, max) = (limits.min(), limits.max());
+            println!("min: {}x{}", min.width, min.height);
+            println!("max: {}x{}", max.width, max.height);
+            ExitCode::SUCCESS
+        }
+        Ok(ControlResponse::Limits(None)) => {
+            println!("no limits requested");
+            ExitCode::SUCCESS
+        }
+        Ok(ControlResponse::Log(lines)) => {
+            for line in lines {
+                match line.stream {
+                    LogStream::Stdout => println!("{}", line.line),
+                    LogStream::Stderr => eprintln!("{}", line.line),
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Ok(ControlResponse::Error(error)) => {
+            eprintln!("azalea-ctl: {error}");
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!("azalea-ctl: {instance}: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse(args: &[String]) -> Result<ControlRequest, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let pid = |arg: &str| {
+        arg.parse::<u32>()
+            .map_err(|_| format!("invalid pid: {arg}"))
+    };
+    let number = |arg: &str| {
+        arg.parse::<u32>()
+            .map_err(|_| format!("invalid number: {arg}"))
+    };
+
+    match args.as_slice() {
+        ["list"] => Ok(ControlRequest::List),
+        ["launch", id] => Ok(ControlRequest::Launch {
+            id: id.to_string(),
+            position: None,
+        }),
+        ["launch", id, position] => Ok(ControlRequest::Launch {
+            id: id.to_string(),
+            position: Some(match *position {
+                "start" => AppletPosition::Start,
+                "center" => AppletPosition::Center,
+                "end" => AppletPosition::End,
+                other => return Err(format!("invalid position: {other}")),
+            }),
+        }),
+        ["stop", applet] => Ok(ControlRequest::Stop(pid(applet)?)),
+        ["restart", applet] => Ok(ControlRequest::Restart(pid(applet)?)),
+        ["show", applet] => Ok(ControlRequest::Event(pid(applet)?, AppletEvent::Show)),
+        ["hide", applet] => Ok(ControlRequest::Event(pid(applet)?, AppletEvent::Hide)),
+        ["size", applet, width, height] => Ok(ControlRequest::Event(
+            pid(applet)?,
+            AppletEvent::Size(number(width)?, number(height)?),
+        )),
+        ["direction", applet, direction] => Ok(ControlRequest::Event(
+            pid(applet)?,
+            AppletEvent::Direction(match *direction {
+                "left" => Direction::Left,
+                "right" => Direction::Right,
+                "top" => Direction::Top,
+                "bottom" => Direction::Bottom,
+                other => return Err(format!("invalid direction: {other}")),
+            }),
+        )),
+        ["limits", applet] => Ok(ControlRequest::Limits(pid(applet)?)),
+        ["log", applet] => Ok(ControlRequest::Log(pid(applet)?)),
+        [] => Err("missing command".to_string()),
+        [command, ..] => Err(format!("invalid command: {command}")),
+    }
+}
+
+fn print_applets(applets: &[AppletStatus]) {
+    println!(
+        "{:<8} {:<24} {:<8} {:>5} {:>8} {:>10}  {:<12} LIMITS",
+        "PID", "ID", "POSITION", "ORDER", "RESTARTS", "UPTIME", "INTRINSIC"
+    );
+    for applet in applets {
+        let intrinsic = applet
+            .intrinsic
+            .map(|size| format!("{}x{}", size.width, size.height))
+            .unwrap_or_else(|| "-".to_string());
+        let limits = applet
+            .limits
+            .map(|limits| {
+                let (min, max) = (limits.min(), limits.max());
+                format!("{}x{}..{}x{}", min.width, min.height, max.width, max.height)
+            })
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<8} {:<24} {:<8} {:>5} {:>8} {:>10}  {:<12} {}",
+            applet.pid,
+            applet.id,
+            format!("{:?}", applet.position),
+            applet.order,
+            applet.restarts,
+            if applet.restarting {
+                "restarting".to_string()
+            } else {
+                format!("{}s", applet.uptime.as_secs())
+            },
+            intrinsic,
+            limits
+        );
+    }
+}
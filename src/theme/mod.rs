@@ -30,6 +30,28 @@ pub enum ThemeType {
     Custom(String),
 }
 
+impl ThemeType {
+    /// Returns whether this type of theme is dark, resolving
+    /// [`ThemeType::System`] against the system's color scheme, or `None` for
+    /// custom themes, which are as dark as their colors, see
+    /// [`Theme::has_dark_background`].
+    pub fn is_dark(&self) -> Option<bool> {
+        match self {
+            ThemeType::Dark => Some(true),
+            ThemeType::Light => Some(false),
+            ThemeType::System => Some(matches!(
+                SYSTEM_PREFERENCES.load().color_scheme,
+                mundy::ColorScheme::Dark
+            )),
+            ThemeType::Custom(_) => None,
+        }
+    }
+}
+
+/// The relative luminance below which light text contrasts better with a
+/// background than dark text.
+const DARK_LUMINANCE: f32 = 0.179;
+
 pub static SYSTEM_PREFERENCES: LazyLock<ArcSwap<Preferences>> = LazyLock::new(|| {
     use std::time::Duration;
     ArcSwap::new(Arc::new(
@@ -95,6 +117,29 @@ pub struct Theme {
 }
 
 impl Theme {
+    /// Returns whether the surface color of the theme is dark, i.e. whether
+    /// light text contrasts better with it than dark text.
+    pub fn has_dark_background(&self) -> bool {
+        let linear = |channel: f32| {
+            if channel <= 0.04045 {
+                channel / 12.92
+            } else {
+                ((channel + 0.055) / 1.055).powf(2.4)
+            }
+        };
+        let Color { r, g, b, .. } = self.surface;
+        let luminance = 0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b);
+        luminance < DARK_LUMINANCE
+    }
+
+    /// Returns whether the theme is dark, judging custom themes by their
+    /// background.
+    pub fn is_dark_scheme(&self) -> bool {
+        self.theme_type
+            .is_dark()
+            .unwrap_or_else(|| self.has_dark_background())
+    }
+
     pub fn system() -> Self {
         let seed = SYSTEM_PREFERENCES
             .load()
@@ -108,6 +153,12 @@ impl Theme {
     }
 
     pub fn from_seed(seed: Color, theme_type: ThemeType) -> Self {
+        let [primary, secondary, tertiary] = Self::seed_colors(seed);
+        Self::from_colors(primary, secondary, tertiary, theme_type)
+    }
+
+    /// Returns the primary, secondary and tertiary colors derived from `seed`.
+    pub fn seed_colors(seed: Color) -> [Color; 3] {
         let primary = seed.with_chroma(0.13);
         let tertiary = {
             let mut oklch = seed.to_oklch();
@@ -115,7 +166,7 @@ impl Theme {
             Color::from_oklch(oklch).with_chroma(0.13)
         };
         let secondary = seed.with_chroma(0.03);
-        Self::from_colors(primary, secondary, tertiary, theme_type)
+        [primary, secondary, tertiary]
     }
 
     pub fn from_colors(
@@ -260,7 +311,7 @@ impl Theme {
         Self {
             theme_type: ThemeType::Light,
             name: String::from("Light"),
-            is_dark: true,
+            is_dark: false,
             primary: primary.color40,
             on_primary: primary.color100,
             primary_container: primary.color90,